@@ -1,16 +1,17 @@
 use net2::UdpBuilder;
 #[cfg(not(windows))]
 use net2::unix::UnixUdpBuilderExt;
+use mio;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
-use super::MDNS_PORT;
+use net::{self, Interface};
 
 pub enum Inet {}
 pub enum Inet6 {}
 
 pub trait AddressFamily {
-    fn bind() -> io::Result<UdpSocket> {
-        let addr = SocketAddr::new(Self::any_addr(), MDNS_PORT);
+    fn bind(port: u16) -> io::Result<UdpSocket> {
+        let addr = SocketAddr::new(Self::any_addr(), port);
         info!("Socket builder");
         let builder = Self::socket_builder()?;
         info!("Reuse address");
@@ -27,19 +28,35 @@ pub trait AddressFamily {
             }
         }
         info!("Bind socket");
-        let socket = builder.bind(&addr)?;
-        info!("Join multicast");
-        Self::join_multicast(&socket)?;
-        Ok(socket)
+        builder.bind(&addr)
     }
 
+    /// Joins the multicast `group` on `interface`, or on the interface the
+    /// kernel picks if `None`
+    fn join_multicast(socket: &mio::net::UdpSocket, group: IpAddr, interface: Option<&Interface>) -> io::Result<()>;
+    fn leave_multicast(socket: &mio::net::UdpSocket, group: IpAddr, interface: Option<&Interface>) -> io::Result<()>;
+    /// Sends multicast on the interface with index `interface` from now on,
+    /// or on the interface the kernel picks if `None`
+    fn set_multicast_if(socket: &mio::net::UdpSocket, interface: Option<u32>) -> io::Result<()>;
+
     fn socket_builder() -> io::Result<UdpBuilder>;
     fn any_addr() -> IpAddr;
+    /// The standard mDNS group of the family
     fn mdns_group() -> IpAddr;
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()>;
     fn v6() -> bool;
 }
 
+fn wrong_family() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "multicast group of the wrong address family")
+}
+
+fn ipv4(interface: Option<&Interface>) -> Ipv4Addr {
+    match interface.and_then(Interface::ip) {
+        Some(IpAddr::V4(ip)) => ip,
+        _ => Ipv4Addr::new(0,0,0,0),
+    }
+}
+
 impl AddressFamily for Inet {
     fn socket_builder() -> io::Result<UdpBuilder> {
         UdpBuilder::new_v4()
@@ -50,11 +67,20 @@ impl AddressFamily for Inet {
     fn mdns_group() -> IpAddr {
         IpAddr::V4(Ipv4Addr::new(224,0,0,251))
     }
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()> {
-        socket.join_multicast_v4(
-            &Ipv4Addr::new(224,0,0,251),
-            &Ipv4Addr::new(0,0,0,0),
-        )
+    fn join_multicast(socket: &mio::net::UdpSocket, group: IpAddr, interface: Option<&Interface>) -> io::Result<()> {
+        match group {
+            IpAddr::V4(group) => socket.join_multicast_v4(&group, &ipv4(interface)),
+            IpAddr::V6(_) => Err(wrong_family()),
+        }
+    }
+    fn leave_multicast(socket: &mio::net::UdpSocket, group: IpAddr, interface: Option<&Interface>) -> io::Result<()> {
+        match group {
+            IpAddr::V4(group) => socket.leave_multicast_v4(&group, &ipv4(interface)),
+            IpAddr::V6(_) => Err(wrong_family()),
+        }
+    }
+    fn set_multicast_if(socket: &mio::net::UdpSocket, interface: Option<u32>) -> io::Result<()> {
+        net::set_multicast_if_v4(socket, interface.unwrap_or(0))
     }
     fn v6() -> bool {
         false
@@ -71,11 +97,20 @@ impl AddressFamily for Inet6 {
     fn mdns_group() -> IpAddr {
         IpAddr::V6(Ipv6Addr::new(0xff02,0,0,0,0,0,0,0xfb))
     }
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()> {
-        socket.join_multicast_v6(
-            &Ipv6Addr::new(0xff02,0,0,0,0,0,0,0xfb),
-            0
-        )
+    fn join_multicast(socket: &mio::net::UdpSocket, group: IpAddr, interface: Option<&Interface>) -> io::Result<()> {
+        match group {
+            IpAddr::V6(group) => socket.join_multicast_v6(&group, interface.map_or(0, Interface::index)),
+            IpAddr::V4(_) => Err(wrong_family()),
+        }
+    }
+    fn leave_multicast(socket: &mio::net::UdpSocket, group: IpAddr, interface: Option<&Interface>) -> io::Result<()> {
+        match group {
+            IpAddr::V6(group) => socket.leave_multicast_v6(&group, interface.map_or(0, Interface::index)),
+            IpAddr::V4(_) => Err(wrong_family()),
+        }
+    }
+    fn set_multicast_if(socket: &mio::net::UdpSocket, interface: Option<u32>) -> io::Result<()> {
+        net::set_multicast_if_v6(socket, interface.unwrap_or(0))
     }
     fn v6() -> bool {
         true
@@ -6,9 +6,12 @@ use super::{Opcode, ResponseCode, Header, Name, RRData, QueryType, QueryClass};
 
 pub enum Questions {}
 pub enum Answers {}
-#[allow(dead_code)] pub enum Nameservers {}
+pub enum Nameservers {}
 pub enum Additional {}
 
+/// Top bit of the class field in mDNS resource records (RFC 6762 §10.2)
+const CACHE_FLUSH: u16 = 0x8000;
+
 pub trait MoveTo<T> { }
 impl <T> MoveTo<T> for T {}
 
@@ -36,7 +39,6 @@ impl Builder<Questions> {
     ///
     /// Initially all sections are empty. You're expected to fill
     /// the questions section with `add_question`
-    #[allow(dead_code)]
     pub fn new_query(id: u16, recursion: bool) -> Builder<Questions> {
         let mut buf = Vec::with_capacity(512);
         let head = Header {
@@ -82,11 +84,12 @@ impl Builder<Questions> {
 
 impl <T> Builder<T> {
     fn write_rr(&mut self, name: &Name,
-        cls: QueryClass, ttl: u32, data: &RRData) {
+        cls: QueryClass, cache_flush: bool, ttl: u32, data: &RRData) {
 
         name.write_to(&mut self.buf).unwrap();
         self.buf.write_u16::<BigEndian>(data.typ() as u16).unwrap();
-        self.buf.write_u16::<BigEndian>(cls as u16).unwrap();
+        let flush = if cache_flush { CACHE_FLUSH } else { 0 };
+        self.buf.write_u16::<BigEndian>(cls as u16 | flush).unwrap();
         self.buf.write_u32::<BigEndian>(ttl).unwrap();
 
         let size_offset = self.buf.len();
@@ -131,13 +134,6 @@ impl <T> Builder<T> {
     pub fn set_max_size(&mut self, max_size: Option<usize>) {
         self.max_size = max_size;
     }
-
-    pub fn is_empty(&self) -> bool {
-        Header::question_count(&self.buf) == 0 &&
-            Header::answer_count(&self.buf) == 0 &&
-            Header::nameserver_count(&self.buf) == 0 &&
-            Header::additional_count(&self.buf) == 0
-    }
 }
 
 impl <T: MoveTo<Questions>> Builder<T> {
@@ -146,7 +142,6 @@ impl <T: MoveTo<Questions>> Builder<T> {
     /// # Panics
     ///
     /// * There are already 65535 questions in the buffer.
-    #[allow(dead_code)]
     pub fn add_question(self, qname: &Name,
         qtype: QueryType, qclass: QueryClass)
         -> Builder<Questions>
@@ -163,13 +158,18 @@ impl <T: MoveTo<Questions>> Builder<T> {
 }
 
 impl <T: MoveTo<Answers>> Builder<T> {
+    /// Adds an answer to the packet
+    ///
+    /// `cache_flush` sets the top bit of the class, which tells mDNS
+    /// queriers to replace cached records of this name and type
+    /// (RFC 6762 §10.2).
     pub fn add_answer(self, name: &Name,
-        cls: QueryClass, ttl: u32, data: &RRData)
+        cls: QueryClass, cache_flush: bool, ttl: u32, data: &RRData)
         -> Builder<Answers>
     {
         let mut builder = self.move_to::<Answers>();
 
-        builder.write_rr(name, cls, ttl, data);
+        builder.write_rr(name, cls, cache_flush, ttl, data);
         Header::inc_answers(&mut builder.buf)
             .expect("Too many answers");
 
@@ -178,14 +178,13 @@ impl <T: MoveTo<Answers>> Builder<T> {
 }
 
 impl <T: MoveTo<Nameservers>> Builder<T> {
-    #[allow(dead_code)]
     pub fn add_nameserver(self, name: &Name,
         cls: QueryClass, ttl: u32, data: &RRData)
         -> Builder<Nameservers>
     {
         let mut builder = self.move_to::<Nameservers>();
 
-        builder.write_rr(name, cls, ttl, data);
+        builder.write_rr(name, cls, false, ttl, data);
         Header::inc_nameservers(&mut builder.buf)
             .expect("Too many nameservers");
 
@@ -193,16 +192,16 @@ impl <T: MoveTo<Nameservers>> Builder<T> {
     }
 }
 
-impl Builder<Additional> {
-    #[allow(dead_code)]
+impl <T: MoveTo<Additional>> Builder<T> {
+    /// Adds an additional record, see `add_answer` for `cache_flush`
     pub fn add_additional(self, name: &Name,
-        cls: QueryClass, ttl: u32, data: &RRData)
+        cls: QueryClass, cache_flush: bool, ttl: u32, data: &RRData)
         -> Builder<Additional>
     {
         let mut builder = self.move_to::<Additional>();
 
-        builder.write_rr(name, cls, ttl, data);
-        Header::inc_nameservers(&mut builder.buf)
+        builder.write_rr(name, cls, cache_flush, ttl, data);
+        Header::inc_additional(&mut builder.buf)
             .expect("Too many additional answers");
 
         builder
@@ -232,4 +231,20 @@ mod test {
             \x0c_xmpp-server\x04_tcp\x05gmail\x03com\x00\x00!\x00\x01";
         assert_eq!(&bld.build().unwrap()[..], &result[..]);
     }
+
+    #[test]
+    fn build_additional() {
+        let name = Name::from_str("a.local").unwrap();
+        let bld = Builder::new_response(0, false, true)
+            .add_answer(&name, QueryClass::IN, true, 10, &RRData::TXT(b"\x00"))
+            .add_additional(&name, QueryClass::IN, false, 10, &RRData::TXT(b"\x00"));
+        let packet = bld.build().unwrap();
+        // class IN with the cache-flush bit, then class IN without it
+        assert_eq!(&packet[23..25], b"\x80\x01");
+        assert_eq!(&packet[43..45], b"\x00\x01");
+        let header = Header::parse(&packet).unwrap();
+        assert_eq!(header.answers, 1);
+        assert_eq!(header.nameservers, 0);
+        assert_eq!(header.additional, 1);
+    }
 }
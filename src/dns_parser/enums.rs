@@ -41,6 +41,8 @@ pub enum Type {
     SRV = 33,
     /// EDNS0 options (RFC 6891)
     OPT = 41,
+    /// next secure record, asserts which types exist (RFC 4034)
+    NSEC = 47,
 }
 
 /// The QTYPE value according to RFC 1035
@@ -263,6 +265,7 @@ impl Type {
             28  => Ok(AAAA),
             33  => Ok(SRV),
             41  => Ok(OPT),
+            47  => Ok(NSEC),
             x => Err(Error::InvalidType(x)),
         }
     }
@@ -83,23 +83,7 @@ impl Header {
     }
     pub fn set_truncated(data: &mut [u8]) {
         let oldflags = BigEndian::read_u16(&data[2..4]);
-        BigEndian::write_u16(&mut data[2..4], oldflags & flag::TRUNCATED);
-    }
-
-    pub fn question_count(data: &[u8]) -> u16 {
-        BigEndian::read_u16(&data[4..6])
-    }
-
-    pub fn answer_count(data: &[u8]) -> u16 {
-        BigEndian::read_u16(&data[6..8])
-    }
-
-    pub fn nameserver_count(data: &[u8]) -> u16 {
-        BigEndian::read_u16(&data[8..10])
-    }
-
-    pub fn additional_count(data: &[u8]) -> u16 {
-        BigEndian::read_u16(&data[10..12])
+        BigEndian::write_u16(&mut data[2..4], oldflags | flag::TRUNCATED);
     }
 
     pub fn inc_questions(data: &mut [u8]) -> Option<u16> {
@@ -132,7 +116,6 @@ impl Header {
         }
     }
 
-    #[allow(dead_code)]
     pub fn inc_additional(data: &mut [u8]) -> Option<u16> {
         let oldq = BigEndian::read_u16(&data[10..12]);
         if oldq < 65535 {
@@ -40,12 +40,21 @@ impl<'a> Packet<'a> {
         for _ in 0..header.nameservers {
             nameservers.push(parse_record(data, &mut offset)?);
         }
+        let mut additional = Vec::with_capacity(header.additional as usize);
+        for _ in 0..header.additional {
+            // EDNS0 OPT records store the payload size in the class field,
+            // skip the rest of the section rather than rejecting the packet
+            match parse_record(data, &mut offset) {
+                Ok(record) => additional.push(record),
+                Err(_) => break,
+            }
+        }
         Ok(Packet {
             header: header,
             questions: questions,
             answers: answers,
             nameservers: nameservers,
-            additional: Vec::new(), // TODO(tailhook)
+            additional,
         })
     }
 }
@@ -501,6 +510,14 @@ mod test {
                 ref x => panic!("Wrong rdata {:?}", x),
             }
         }
+        assert_eq!(packet.additional.len(), 2);
+        assert_eq!(&packet.additional[0].name.to_string()[..], "cf-dns01.sstatic.net");
+        match packet.additional[1].data {
+            RRData::A(addr) => {
+                assert_eq!(addr, Ipv4Addr::new(173, 245, 59, 4));
+            }
+            ref x => panic!("Wrong rdata {:?}", x),
+        }
     }
 
     #[test]
@@ -17,6 +17,7 @@ pub enum RRData<'a> {
     SRV { priority: u16, weight: u16, port: u16, target: Name<'a> },
     MX { preference: u16, exchange: Name<'a> },
     TXT(&'a [u8]),
+    NSEC { next: Name<'a>, types: Vec<Type> },
     // Anything that can't be parsed yet
     Unknown { typ: Type, data: &'a [u8] },
 }
@@ -50,6 +51,9 @@ impl<'a> ::std::fmt::Display for RRData<'a> {
             TXT(txt) => {
                 write!(fmt, "TXT '{}'", String::from_utf8_lossy(txt))
             }
+            NSEC { next, types } => {
+                write!(fmt, "NSEC {} {:?}", next, types)
+            }
             Unknown{ typ, data } => {
                 write!(fmt, "{:?} '{}' {:?}", typ, String::from_utf8_lossy(data), data)
             }
@@ -68,6 +72,7 @@ impl<'a> RRData<'a> {
             RRData::SRV { .. } => Type::SRV,
             RRData::MX { .. } => Type::MX,
             RRData::TXT(..) => Type::TXT,
+            RRData::NSEC { .. } => Type::NSEC,
             RRData::Unknown { typ, .. } => typ,
         }
     }
@@ -97,6 +102,24 @@ impl<'a> RRData<'a> {
                 exchange.write_to(writer)
             }
             RRData::TXT(data) => writer.write_all(data),
+            RRData::NSEC { ref next, ref types } => {
+                next.write_to(writer)?;
+                // all the types we know are below 256, so the bitmap only
+                // ever needs window 0 (the restricted form of RFC 6762 §6.1)
+                let mut bitmap = [0u8; 32];
+                let mut len = 0;
+                for &typ in types {
+                    let code = typ as usize;
+                    bitmap[code / 8] |= 0x80 >> (code % 8);
+                    len = len.max(code / 8 + 1);
+                }
+                if len > 0 {
+                    writer.write_u8(0)?;
+                    writer.write_u8(len as u8)?;
+                    writer.write_all(&bitmap[..len])?;
+                }
+                Ok(())
+            }
             RRData::Unknown { data, .. } => writer.write_all(data),
         }
     }
@@ -157,6 +180,34 @@ impl<'a> RRData<'a> {
                 })
             }
             Type::TXT => Ok(RRData::TXT(rdata)),
+            Type::NSEC => {
+                let (next, mut offset) = Name::scan(rdata, original)?;
+                let mut types = Vec::new();
+                while offset < rdata.len() {
+                    if offset + 2 > rdata.len() {
+                        return Err(Error::WrongRdataLength);
+                    }
+                    let window = u16::from(rdata[offset]) << 8;
+                    let len = rdata[offset + 1] as usize;
+                    offset += 2;
+                    if len == 0 || len > 32 || offset + len > rdata.len() {
+                        return Err(Error::WrongRdataLength);
+                    }
+                    for (i, &byte) in rdata[offset..offset + len].iter().enumerate() {
+                        for bit in 0..8 {
+                            if byte & (0x80 >> bit) != 0 {
+                                // types we don't know can't be listed
+                                let code = window | (i * 8 + bit) as u16;
+                                if let Ok(typ) = Type::parse(code) {
+                                    types.push(typ);
+                                }
+                            }
+                        }
+                    }
+                    offset += len;
+                }
+                Ok(RRData::NSEC { next, types })
+            }
             typ => {
                 Ok(RRData::Unknown {
                     typ: typ,
@@ -166,3 +217,25 @@ impl<'a> RRData<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nsec_restricted_bitmap() {
+        let name = Name::from_str("host.local").unwrap();
+        let nsec = RRData::NSEC { next: name, types: vec![Type::A, Type::AAAA] };
+        let mut buf = Vec::new();
+        nsec.write_to(&mut buf).unwrap();
+        assert_eq!(&buf[..], &b"\x04host\x05local\x00\x00\x04\x40\x00\x00\x08"[..]);
+
+        match RRData::parse(Type::NSEC, &buf, &buf).unwrap() {
+            RRData::NSEC { next, types } => {
+                assert_eq!(next.to_string(), "host.local");
+                assert_eq!(types, vec![Type::A, Type::AAAA]);
+            }
+            x => panic!("Wrong rdata {:?}", x),
+        }
+    }
+}
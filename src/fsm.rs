@@ -1,63 +1,425 @@
-use dns_parser::{self, QueryClass, QueryType, Name, RRData};
+use dns_parser::{self, QueryClass, QueryType, Name, RRData, Type};
 use log;
-use std::collections::VecDeque;
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
 use std::io;
 use std::marker::PhantomData;
+use std::mem;
 use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 use futures::{Poll, Async, Future, Stream};
 use futures::sync::mpsc;
-use tokio::net::UdpSocket;
-use tokio::reactor::Handle;
+use mio;
+use mio::net::UdpSocket;
+use rand::{Rng, thread_rng};
+use tokio::reactor::{Handle, PollEvented2};
+use tokio::timer::Delay;
 
-use super::{DEFAULT_TTL, MDNS_PORT};
+use super::into_io_error;
 use address_family::AddressFamily;
+use interface_filter::InterfaceFilter;
 use net;
-use services::{Services, ServiceData};
+use record::{Record, Response, rdata_bytes};
+use services::{NameChange, Services, ServicesInner, ServiceData};
 
-pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
+/// Number of probe queries sent before a name is considered ours (RFC 6762 §8.1)
+const PROBE_COUNT: u8 = 3;
+/// Interval between probe queries, in milliseconds
+const PROBE_INTERVAL: u64 = 250;
+/// How long the loser of a probe tiebreak waits before probing again
+const PROBE_DEFER: u64 = 1000;
+/// Delay before the second announcement, doubled for every further one
+const ANNOUNCE_INTERVAL: u64 = 1000;
+/// Upper bound on the number of announcements (RFC 6762 §8.3)
+pub const MAX_ANNOUNCEMENTS: u8 = 8;
+/// Range of the random delay before answering with shared records, in
+/// milliseconds
+const ANSWER_DELAY_MIN: u64 = 20;
+const ANSWER_DELAY_MAX: u64 = 120;
+/// Default minimum interval between multicasts of the same record, in
+/// milliseconds
+const RATE_LIMIT: u64 = 1000;
+/// Default size limit of outgoing messages, fits a 1500 byte Ethernet MTU
+/// with IPv6 and UDP headers
+const DEFAULT_PACKET_SIZE: usize = 1440;
+/// Largest message allowed by RFC 6762 §17 for jumbo frames, which is
+/// 9000 bytes including the IPv6 and UDP headers
+pub const MAX_PACKET_SIZE: usize = 8952;
+/// Maximum TTL in answers to legacy unicast queries (RFC 6762 §6.7)
+const LEGACY_TTL: u32 = 10;
+/// Size limit of answers to legacy unicast queries, resolvers without EDNS0
+/// accept no more (RFC 1035 §4.2.1)
+const LEGACY_PACKET_SIZE: usize = 512;
+/// Minimum time a truncated query is held for its remaining known answers
+const KNOWN_ANSWER_WAIT: u64 = 400;
+/// How long the interface list is reused where address changes aren't
+/// watched, in milliseconds
+const INTERFACE_REFRESH: u64 = 1000;
+
+/// Settings of an FSM, see `ResponderBuilder`
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub ttl: u32,
+    /// Multicast group and port, of the FSM's address family
+    pub group: SocketAddr,
+    pub announcements: u8,
+    pub filter: InterfaceFilter,
+}
 
 #[derive(Clone, Debug)]
 pub enum Command {
     SendUnsolicited {
+        id: usize,
         svc: ServiceData,
         ttl: u32,
         include_ip: bool
     },
+    Probe {
+        id: usize,
+    },
+    SetAnnouncements {
+        count: u8,
+    },
+    SetRateLimit {
+        interval: Duration,
+    },
+    SetMaxPacketSize {
+        size: usize,
+    },
+    SetInterfaces {
+        filter: InterfaceFilter,
+    },
     Shutdown,
 }
 
+/// What owns the name being probed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum ProbeTarget {
+    /// The host name and its address records
+    Host,
+    /// The registered service with the given id
+    Service(usize),
+}
+
+/// A name being probed for uniqueness on the link
+///
+/// Records under the name stay invisible to queries until probing succeeds.
+struct Probe {
+    target: ProbeTarget,
+    name: Name<'static>,
+    sent: u8,
+    next: Instant,
+}
+
+/// Announcements still due for a name that was successfully probed
+struct Announcement {
+    target: ProbeTarget,
+    remaining: u8,
+    interval: u64,
+    next: Instant,
+}
+
+/// A truncated query waiting for the rest of its known answers
+struct PendingQuery {
+    addr: SocketAddr,
+    interface: Option<u32>,
+    packets: Vec<Vec<u8>>,
+    deadline: Instant,
+}
+
+/// Multicast answers to queries that arrived on one interface, dropped if
+/// another responder sends them first
+struct QueuedAnswers {
+    interface: Option<u32>,
+    response: Response,
+    due: Instant,
+}
+
 pub struct FSM<AF: AddressFamily> {
-    socket: UdpSocket,
+    socket: PollEvented2<UdpSocket>,
+    group: SocketAddr,
+    ttl: u32,
     services: Services,
     //hostname: String,
     commands: mpsc::UnboundedReceiver<Command>,
-    outgoing: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// Names given up after conflicts, ours or another FSM's
+    name_changes: mpsc::UnboundedReceiver<NameChange>,
+    /// Packets to send, with the index of the interface multicast goes
+    /// out on
+    outgoing: VecDeque<(Vec<u8>, SocketAddr, Option<u32>)>,
+    /// Interfaces we joined the mDNS group on
+    interfaces: Vec<net::Interface>,
+    /// Whether we joined on the interface the kernel picks instead, as no
+    /// other one could be used
+    default_interface: bool,
+    filter: InterfaceFilter,
+    /// Notifies us of address and link changes, where supported
+    watcher: Option<PollEvented2<net::AddressWatcher>>,
+    /// Every interface address of either family, as of the last address
+    /// change, and when it was read
+    ifaces: Vec<net::Interface>,
+    ifaces_read: Instant,
+    /// Addresses of our family we advertise, with their interface index
+    addrs: Vec<(u32, IpAddr)>,
+    probes: Vec<Probe>,
+    /// Names probed successfully under their current name, the only ones
+    /// answered for and announced
+    confirmed: HashSet<ProbeTarget>,
+    announcements: Vec<Announcement>,
+    announcement_count: u8,
+    pending: Vec<PendingQuery>,
+    answers: Vec<QueuedAnswers>,
+    /// Records multicast recently, the interface they went out on and
+    /// when, for rate limiting
+    last_multicast: Vec<(Option<u32>, Record, Instant)>,
+    rate_limit: Duration,
+    max_packet_size: usize,
+    timer: Delay,
+    shutting_down: bool,
     _af: PhantomData<AF>,
 }
 
 impl <AF: AddressFamily> FSM<AF> {
-    pub fn new(handle: &Handle, services: &Services)
+    pub fn new(handle: &Handle, services: &Services, config: &Config)
         -> io::Result<(FSM<AF>, mpsc::UnboundedSender<Command>)>
     {
         info!("Binding socket");
-        let std_socket = AF::bind()?;
+        let std_socket = AF::bind(config.group.port())?;
         info!("Creating async socket");
-        let socket = UdpSocket::from_std(std_socket, handle)?;
+        let socket = UdpSocket::from_socket(std_socket)?;
+        net::set_pktinfo(&socket, AF::v6())?;
+        let socket = PollEvented2::new_with_handle(socket, handle)?;
+        let watcher = net::AddressWatcher::new()
+            .and_then(|watcher| PollEvented2::new_with_handle(watcher, handle));
+        let watcher = match watcher {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                info!("Not watching address changes: {:?}", err);
+                None
+            }
+        };
+        let (mut fsm, tx) = FSM::with_socket(socket, watcher, services, config);
+        fsm.update_interfaces()?;
+        fsm.addrs = fsm.family_addrs();
+        fsm.start_probe(ProbeTarget::Host);
+
+        Ok((fsm, tx))
+    }
+
+    /// An FSM sending and receiving on `socket`, before it joins the mDNS
+    /// group and starts probing
+    fn with_socket(socket: PollEvented2<UdpSocket>, watcher: Option<PollEvented2<net::AddressWatcher>>,
+                   services: &Services, config: &Config)
+        -> (FSM<AF>, mpsc::UnboundedSender<Command>)
+    {
         let (tx, rx) = mpsc::unbounded();
 
         let fsm = FSM {
             socket: socket,
+            group: config.group,
+            ttl: config.ttl,
             services: services.clone(),
             commands: rx,
+            name_changes: services.write().unwrap().subscribe_name_changes(),
             outgoing: VecDeque::new(),
+            interfaces: Vec::new(),
+            default_interface: false,
+            filter: config.filter.clone(),
+            watcher,
+            ifaces: net::getifaddrs().collect(),
+            ifaces_read: Instant::now(),
+            addrs: Vec::new(),
+            probes: Vec::new(),
+            confirmed: HashSet::new(),
+            announcements: Vec::new(),
+            announcement_count: config.announcements,
+            pending: Vec::new(),
+            answers: Vec::new(),
+            last_multicast: Vec::new(),
+            rate_limit: Duration::from_millis(RATE_LIMIT),
+            max_packet_size: DEFAULT_PACKET_SIZE,
+            timer: Delay::new(Instant::now()),
+            shutting_down: false,
             _af: PhantomData,
         };
 
-        Ok((fsm, tx))
+        (fsm, tx)
+    }
+
+    /// Joins the mDNS group on the usable interfaces the filter allows, and
+    /// leaves it on those that are gone or no longer allowed
+    ///
+    /// Without any such interface the kernel picks one, unless the filter
+    /// excludes some.
+    fn update_interfaces(&mut self) -> io::Result<()> {
+        let wanted = multicast_interfaces::<AF>(&self.ifaces, &self.filter);
+        let socket = self.socket.get_ref();
+        let same = |a: &net::Interface, b: &net::Interface| a.index() == b.index() && a.ip() == b.ip();
+
+        for iface in &self.interfaces {
+            if !wanted.iter().any(|other| same(iface, other)) {
+                info!("Leave multicast on {} {:?}", iface.name(), iface.ip());
+                if let Err(err) = AF::leave_multicast(socket, self.group.ip(), Some(iface)) {
+                    warn!("cannot leave multicast on {}: {:?}", iface.name(), err);
+                }
+            }
+        }
+
+        let mut joined = Vec::new();
+        for iface in wanted {
+            if self.interfaces.iter().any(|other| same(&iface, other)) {
+                joined.push(iface);
+                continue;
+            }
+
+            info!("Join multicast on {} {:?}", iface.name(), iface.ip());
+            match AF::join_multicast(socket, self.group.ip(), Some(&iface)) {
+                Ok(()) => joined.push(iface),
+                Err(err) => warn!("cannot join multicast on {}: {:?}", iface.name(), err),
+            }
+        }
+        self.interfaces = joined;
+
+        let use_default = self.interfaces.is_empty() && self.filter == InterfaceFilter::new();
+        if use_default && !self.default_interface {
+            info!("Join multicast");
+            match AF::join_multicast(socket, self.group.ip(), None) {
+                Ok(()) => self.default_interface = true,
+                // Joining is tried again once the watcher reports a change
+                Err(ref err) if self.watcher.is_some() => warn!("cannot join multicast: {:?}", err),
+                Err(err) => return Err(err),
+            }
+        } else if !use_default && self.default_interface {
+            info!("Leave multicast");
+            if let Err(err) = AF::leave_multicast(socket, self.group.ip(), None) {
+                warn!("cannot leave multicast: {:?}", err);
+            }
+            self.default_interface = false;
+        }
+
+        Ok(())
+    }
+
+    /// Catches up with changed addresses, interfaces or filter
+    ///
+    /// Goodbyes go out for addresses we no longer have and new ones are
+    /// announced. When an interface went up or down all our names are
+    /// probed again (RFC 6762 §8.3), as we may have joined a network where
+    /// they are taken.
+    fn handle_address_changes(&mut self, link_changed: bool) {
+        self.read_interfaces();
+        let addrs = self.family_addrs();
+        let removed: Vec<(u32, IpAddr)> = self.addrs.iter()
+            .filter(|addr| !addrs.contains(addr))
+            .cloned()
+            .collect();
+        let added = addrs.iter().any(|addr| !self.addrs.contains(addr));
+        self.addrs = addrs;
+
+        let hostname = self.services.read().unwrap().get_hostname().clone();
+        if self.confirmed.contains(&ProbeTarget::Host) {
+            // goodbyes before leaving the group, while the interface is
+            // still known
+            let group = self.group;
+            for &(index, ip) in &removed {
+                debug!("address {} on interface {} removed", ip, index);
+                let mut response = Response::new();
+                response.add_answer(Record::new(&hostname, 0, &ip_to_rdata(ip)));
+                self.send_response(0, &response, group, Some(index));
+            }
+        }
+
+        if let Err(err) = self.update_interfaces() {
+            warn!("cannot join multicast: {:?}", err);
+        }
+
+        if link_changed {
+            debug!("link changed, probing again");
+            let ids: Vec<usize> = self.services.read().unwrap().ids().cloned().collect();
+            self.start_probe(ProbeTarget::Host);
+            for id in ids {
+                self.start_probe(ProbeTarget::Service(id));
+            }
+        } else if added && self.confirmed.contains(&ProbeTarget::Host) {
+            self.start_announcements(ProbeTarget::Host);
+        }
+    }
+
+    fn read_interfaces(&mut self) {
+        self.ifaces = net::getifaddrs().collect();
+        self.ifaces_read = Instant::now();
+    }
+
+    /// Whether the filter allows the interface with index `index`, queries
+    /// arriving on other interfaces are ignored
+    fn is_allowed(&self, index: u32) -> bool {
+        !self.ifaces.iter()
+            .any(|iface| iface.index() == index && !self.filter.is_allowed(iface.name(), index))
+    }
+
+    /// Addresses of the usable interfaces the filter allows, with the index
+    /// of their interface
+    fn interface_addrs(&self) -> Vec<(u32, IpAddr)> {
+        self.ifaces.iter()
+            .filter(|iface| is_usable(iface))
+            .filter(|iface| self.filter.is_allowed(iface.name(), iface.index()))
+            .filter_map(|iface| iface.ip().map(|ip| (iface.index(), ip)))
+            .collect()
+    }
+
+    /// Like `interface_addrs`, of the address family only
+    fn family_addrs(&self) -> Vec<(u32, IpAddr)> {
+        self.interface_addrs().into_iter()
+            .filter(|&(_, ip)| ip.is_ipv6() == AF::v6())
+            .collect()
+    }
+
+    /// Like `interface_addrs`, only those of the interface with index
+    /// `interface` if given
+    ///
+    /// An interface without any, like the loopback, gets all of them, so
+    /// that local queries are still answered.
+    fn host_addrs(&self, interface: Option<u32>) -> Vec<IpAddr> {
+        let addrs = self.interface_addrs();
+        let on_interface: Vec<IpAddr> = addrs.iter()
+            .filter(|&&(index, _)| interface.is_none() || interface == Some(index))
+            .map(|&(_, ip)| ip)
+            .collect();
+        if on_interface.is_empty() {
+            addrs.into_iter().map(|(_, ip)| ip).collect()
+        } else {
+            on_interface
+        }
+    }
+
+    /// Address records of one family, see `host_addrs`
+    fn ip_rdata(&self, v6: bool, interface: Option<u32>) -> Vec<RRData<'static>> {
+        self.host_addrs(interface).into_iter()
+            .filter(|ip| ip.is_ipv6() == v6)
+            .map(ip_to_rdata)
+            .collect()
     }
 
-    fn handle_packet(&mut self, buffer: &[u8], addr: SocketAddr) {
-        trace!("received packet from {:?}", addr);
+    /// Handles a packet that arrived on the interface with index
+    /// `interface`, if known
+    fn handle_packet(&mut self, buffer: &[u8], addr: SocketAddr, interface: Option<u32>) {
+        trace!("received packet from {:?} on interface {:?}", addr, interface);
+
+        if self.watcher.is_none() &&
+            self.ifaces_read.elapsed() >= Duration::from_millis(INTERFACE_REFRESH)
+        {
+            self.read_interfaces();
+        }
+
+        // without IP_PKTINFO, guess the interface from the source address
+        let interface = interface.or_else(|| source_interface(&self.ifaces, addr));
+
+        if let Some(index) = interface {
+            if !self.is_allowed(index) {
+                trace!("ignoring packet on excluded interface {}", index);
+                return;
+            }
+        }
 
         let packet = match dns_parser::Packet::parse(buffer) {
             Ok(packet) => {
@@ -71,185 +433,1129 @@ impl <AF: AddressFamily> FSM<AF> {
         };
 
         if !packet.header.query {
-            trace!("received packet from {:?} with no query", addr);
+            self.handle_response(&packet, addr, interface);
+            return;
+        }
+
+        self.handle_probe_query(&packet, addr);
+
+        if addr.port() != self.group.port() {
+            self.answer_legacy(&packet, addr, interface);
+            return;
+        }
+
+        if let Some(pos) = self.pending.iter().position(|query| query.addr == addr) {
+            // more known answers for a query we're holding back
+            self.pending[pos].packets.push(buffer.to_vec());
+            if !packet.header.truncated {
+                let query = self.pending.remove(pos);
+                self.answer_pending(query);
+            }
             return;
         }
 
         if packet.header.truncated {
-            warn!("dropping truncated packet from {:?}", addr);
+            // RFC 6762 §7.2: more known answers follow, wait 400-500ms for them
+            let delay = thread_rng().gen_range(KNOWN_ANSWER_WAIT, KNOWN_ANSWER_WAIT + 100);
+            trace!("holding truncated query from {:?} for {}ms", addr, delay);
+            self.pending.push(PendingQuery {
+                addr,
+                interface,
+                packets: vec![buffer.to_vec()],
+                deadline: Instant::now() + Duration::from_millis(delay),
+            });
             return;
         }
 
-        let mut unicast_builder = dns_parser::Builder::new_response(packet.header.id, false, true).move_to::<dns_parser::Answers>();
-        let mut multicast_builder = dns_parser::Builder::new_response(packet.header.id, false, true).move_to::<dns_parser::Answers>();
-        unicast_builder.set_max_size(None);
-        multicast_builder.set_max_size(None);
+        self.answer_query(&[packet], addr, interface);
+    }
+
+    /// Answers the questions in `packets`, leaving out the records listed
+    /// as known answers in any of them
+    ///
+    /// Only the addresses of the interface the query arrived on are given
+    /// out, as the others may not be reachable from the querier's link.
+    fn answer_query(&mut self, packets: &[dns_parser::Packet], addr: SocketAddr, interface: Option<u32>) {
+        let mut unicast = Response::new();
+        let mut multicast = Response::new();
 
-        for question in packet.questions {
+        for question in packets.iter().flat_map(|packet| &packet.questions) {
             if question.qclass == QueryClass::IN || question.qclass == QueryClass::Any {
                 if question.qu {
-                    unicast_builder = self.handle_question(&question, unicast_builder);
+                    self.handle_question(question, interface, &mut unicast);
                 } else {
-                    multicast_builder = self.handle_question(&question, multicast_builder);
+                    self.handle_question(question, interface, &mut multicast);
                 }
             }
         }
 
-        if !multicast_builder.is_empty() {
-            let response = multicast_builder.build().unwrap_or_else(|x| x);
-            if log::max_level() == log::LevelFilter::Trace {
-                use dns_parser::Packet;
-                match Packet::parse(&response) {
-                    Ok(packet) => {
-                        trace!("Sending multicast {}", packet);
-                    }
-                    Err(e) => {
-                        error!("Error parsing outgoing multicast packet {:?}", e);
-                    }
-                }
+        let is_known = |record: &Record| {
+            packets.iter().any(|packet| record.is_known(&packet.answers))
+        };
+        unicast.retain(|record| !is_known(record));
+        multicast.retain(|record| !is_known(record));
+
+        // probe queries must be answered right away and are allowed to
+        // bypass the rate limit to defend our names
+        if packets.iter().any(|packet| !packet.nameservers.is_empty()) {
+            let group = self.group;
+            self.send_response(0, &multicast, group, interface);
+        } else {
+            self.queue_answers(multicast, interface);
+        }
+        self.send_response(packets[0].header.id, &unicast, addr, interface);
+    }
+
+    /// Answers a query from a plain DNS resolver (RFC 6762 §6.7)
+    ///
+    /// The response goes back to the querier only, looks like a
+    /// conventional unicast DNS response and has short TTLs, as it won't be
+    /// updated by announcements or goodbyes.
+    fn answer_legacy(&mut self, packet: &dns_parser::Packet, addr: SocketAddr, interface: Option<u32>) {
+        let mut response = Response::new();
+        for question in &packet.questions {
+            if question.qclass == QueryClass::IN || question.qclass == QueryClass::Any {
+                self.handle_question(question, interface, &mut response);
             }
-            let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
-            self.outgoing.push_back((response, addr));
         }
 
-        if !unicast_builder.is_empty() {
-            let response = unicast_builder.build().unwrap_or_else(|x| x);
-            if log::max_level() == log::LevelFilter::Trace {
-                use dns_parser::Packet;
-                match Packet::parse(&response) {
-                    Ok(packet) => {
-                        trace!("Sending unicast {}", packet);
-                    }
-                    Err(e) => {
-                        error!("Error parsing outgoing unicast packet {:?}", e);
-                    }
-                }
+        if response.is_empty() {
+            return;
+        }
+
+        let question_size: usize = packet.questions.iter().map(|question| {
+            let mut name = Vec::new();
+            question.qname.write_to(&mut name).unwrap();
+            // type and class
+            name.len() + 4
+        }).sum();
+        let max_size = LEGACY_PACKET_SIZE.saturating_sub(question_size);
+        // only the first part is sent, a single RRset too big for it is left out
+        let part = response.split(max_size).into_iter().next()
+            .filter(|part| part.size() <= max_size)
+            .unwrap_or_default();
+        let truncated = part.answers.len() < response.answers.len();
+
+        let mut builder = dns_parser::Builder::new_response(packet.header.id, false, true);
+        builder.set_max_size(None);
+        for question in &packet.questions {
+            builder = builder.add_question(&question.qname, question.qtype, question.qclass);
+        }
+        let mut builder = builder.move_to::<dns_parser::Answers>();
+        for mut record in part.answers {
+            record.ttl = record.ttl.min(LEGACY_TTL);
+            record.cache_flush = false;
+            builder = record.add_answer(builder);
+        }
+        let mut builder = builder.move_to::<dns_parser::Additional>();
+        for mut record in part.additional {
+            record.ttl = record.ttl.min(LEGACY_TTL);
+            record.cache_flush = false;
+            builder = record.add_additional(builder);
+        }
+
+        let mut packet = builder.build().unwrap_or_else(|x| x);
+        if truncated {
+            dns_parser::Header::set_truncated(&mut packet[..12]);
+        }
+        self.send_packet(packet, addr, interface);
+    }
+
+    fn answer_pending(&mut self, query: PendingQuery) {
+        let mut packets = Vec::new();
+        for buffer in &query.packets {
+            match dns_parser::Packet::parse(buffer) {
+                Ok(packet) => packets.push(packet),
+                Err(error) => warn!("couldn't parse held packet from {:?}: {}", query.addr, error),
             }
-            self.outgoing.push_back((response, addr));
+        }
+
+        if !packets.is_empty() {
+            self.answer_query(&packets, query.addr, query.interface);
         }
     }
 
-    fn handle_question(&self, question: &dns_parser::Question, mut builder: AnswerBuilder) -> AnswerBuilder {
+    /// Schedules multicast answers (RFC 6762 §6)
+    ///
+    /// Answers including shared records are delayed by a random 20-120ms
+    /// so that other responders' answers can suppress ours, answers with
+    /// unique records only go out right away. Everything queued for an
+    /// interface is sent in one packet at its earliest deadline.
+    fn queue_answers(&mut self, response: Response, interface: Option<u32>) {
+        if response.is_empty() {
+            return;
+        }
+
+        let delay = if response.answers.iter().any(Record::is_shared) {
+            thread_rng().gen_range(ANSWER_DELAY_MIN, ANSWER_DELAY_MAX)
+        } else {
+            0
+        };
+        let due = Instant::now() + Duration::from_millis(delay);
+
+        if let Some(queued) = self.answers.iter_mut().find(|queued| queued.interface == interface) {
+            queued.due = queued.due.min(due);
+            queued.response.extend(response);
+        } else {
+            self.answers.push(QueuedAnswers { interface, response, due });
+        }
+    }
+
+    fn handle_answers(&mut self, now: Instant) {
+        let (due, queued): (Vec<_>, Vec<_>) = mem::take(&mut self.answers)
+            .into_iter()
+            .partition(|queued| queued.due <= now);
+        self.answers = queued;
+
+        // RFC 6762 §6: don't multicast a record more than once per second
+        let limit = self.rate_limit;
+        self.last_multicast.retain(|&(_, _, sent)| now.duration_since(sent) < limit);
+
+        // each interface is limited on its own, a record that recently went
+        // out on one may still be due on another
+        let group = self.group;
+        for queued in due {
+            for target in self.send_targets(queued.interface) {
+                let mut response = queued.response.clone();
+                response.retain(|record| {
+                    !self.last_multicast.iter().any(|(iface, sent, _)| *iface == target && sent.same_data(record))
+                });
+                self.send_response(0, &response, group, target);
+            }
+        }
+    }
+
+    fn handle_pending(&mut self, now: Instant) {
+        let pending = mem::take(&mut self.pending);
+        for query in pending {
+            if query.deadline > now {
+                self.pending.push(query);
+            } else {
+                self.answer_pending(query);
+            }
+        }
+    }
+
+    fn handle_question(&self, question: &dns_parser::Question, interface: Option<u32>, response: &mut Response) {
         let services = self.services.read().unwrap();
+        let hostname = services.get_hostname();
 
         match question.qtype {
             QueryType::A |
             QueryType::AAAA |
-            QueryType::All if question.qname == *services.get_hostname() => {
-                builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
+            QueryType::All if question.qname == *hostname => {
+                // RFC 6762 §6.2: addresses of the other family are additional
+                let v6 = question.qtype == QueryType::AAAA;
+                for record in self.ip_records(hostname, self.ttl, v6, interface) {
+                    response.add_answer(record);
+                }
+                for record in self.ip_records(hostname, self.ttl, !v6, interface) {
+                    if question.qtype == QueryType::All {
+                        response.add_answer(record);
+                    } else {
+                        response.add_additional(record);
+                    }
+                }
+                self.add_host_nsec(hostname, interface, response);
+            }
+            _ if question.qname == *hostname => {
+                self.add_host_nsec(hostname, interface, response);
+            }
+            QueryType::PTR |
+            QueryType::All if is_reverse_name(&question.qname) => {
+                if !self.confirmed.contains(&ProbeTarget::Host) {
+                    return;
+                }
+
+                // RFC 6762 §4: reverse mapping of our own addresses
+                let reverse = self.host_addrs(interface).into_iter()
+                    .map(reverse_name)
+                    .find(|name| question.qname == *name);
+                if let Some(name) = reverse {
+                    response.add_answer(Record::new(&name, self.ttl, &RRData::PTR(hostname.clone())));
+                }
             }
             QueryType::PTR => {
-                let mut found = false;
-                if question.qname == Name::from_str("_services._dns-sd._udp.local").unwrap() {
-                    for t in services.types_iter() {
-                        builder = builder.add_answer(&question.qname, QueryClass::IN, DEFAULT_TTL, &RRData::PTR(t.clone()))
+                if !self.add_browse_answers(&services, &question.qname, interface, response) {
+                    trace!("Not found. IN PTR {}", &question.qname);
+                }
+            }
+            QueryType::All => {
+                if let Some(svc) = services.find_by_name(&question.qname) {
+                    if self.is_confirmed(&services, svc) {
+                        response.add_answer(svc.srv_record(hostname, self.ttl));
+                        response.add_answer(svc.txt_record(self.ttl));
+                        response.add_additional(svc.nsec_record(self.ttl));
+                        self.add_ip_additional(hostname, interface, response);
                     }
                 } else {
-                    for svc in services.find_by_type(&question.qname) {
-                        builder = svc.add_ptr_rr(builder, DEFAULT_TTL);
-                        builder = svc.add_srv_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                        builder = svc.add_txt_rr(builder, DEFAULT_TTL);
-                        builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                        found = true;
-                    }
-                    if !found {
-                        trace!("Not found. IN PTR {}", &question.qname);
-                    }
+                    self.add_browse_answers(&services, &question.qname, interface, response);
                 }
             }
             QueryType::SRV => {
                 if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_srv_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
+                    if self.is_confirmed(&services, svc) {
+                        response.add_answer(svc.srv_record(hostname, self.ttl));
+                        response.add_additional(svc.nsec_record(self.ttl));
+                        self.add_ip_additional(hostname, interface, response);
+                    }
                 }
             }
             QueryType::TXT => {
                 if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_txt_rr(builder, DEFAULT_TTL);
+                    if self.is_confirmed(&services, svc) {
+                        response.add_answer(svc.txt_record(self.ttl));
+                        response.add_additional(svc.nsec_record(self.ttl));
+                    }
+                }
+            }
+            _ => {
+                if let Some(svc) = services.find_by_name(&question.qname) {
+                    if self.is_confirmed(&services, svc) {
+                        response.add_additional(svc.nsec_record(self.ttl));
+                    }
                 }
             }
-            _ => ()
+        }
+    }
+
+    /// Adds an NSEC record asserting which address families the host has
+    /// (RFC 6762 §6.1), so that queriers don't wait for the other one
+    fn add_host_nsec(&self, hostname: &Name<'static>, interface: Option<u32>, response: &mut Response) {
+        if !self.confirmed.contains(&ProbeTarget::Host) {
+            return;
         }
 
-        builder
+        let mut types = Vec::new();
+        if !self.ip_rdata(false, interface).is_empty() {
+            types.push(Type::A);
+        }
+        if !self.ip_rdata(true, interface).is_empty() {
+            types.push(Type::AAAA);
+        }
+        // without any address there's nothing to assert
+        if types.is_empty() {
+            return;
+        }
+        let nsec = RRData::NSEC { next: hostname.clone(), types };
+        response.add_additional(Record::new(hostname, self.ttl, &nsec));
     }
 
-    fn add_ip_rr(&self, hostname: &Name, mut builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        for iface in net::getifaddrs() {
-            if iface.is_loopback() {
+    /// Adds the PTR records for a service type or for the list of service
+    /// types, returns `false` if we have none
+    fn add_browse_answers(&self, services: &ServicesInner, name: &Name, interface: Option<u32>,
+                          response: &mut Response) -> bool {
+        let hostname = services.get_hostname();
+        let mut found = false;
+        let services_name = Name::from_str("_services._dns-sd._udp.local").unwrap();
+        if *name == services_name {
+            for t in services.types_iter() {
+                if !services.find_by_type(t).any(|svc| self.is_confirmed(services, svc)) {
+                    continue;
+                }
+                response.add_answer(Record::shared(&services_name, self.ttl, &RRData::PTR(t.clone())));
+                found = true;
+            }
+        } else {
+            // RFC 6763 §12.1: everything needed to connect to the
+            // service goes in the Additional section
+            for svc in services.find_by_type(name) {
+                if !self.is_confirmed(services, svc) {
+                    continue;
+                }
+                response.add_answer(svc.ptr_record(self.ttl));
+                response.add_additional(svc.srv_record(hostname, self.ttl));
+                response.add_additional(svc.txt_record(self.ttl));
+                response.add_additional(svc.nsec_record(self.ttl));
+                self.add_ip_additional(hostname, interface, response);
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Adds the addresses of both families as additional records
+    fn add_ip_additional(&self, hostname: &Name<'static>, interface: Option<u32>, response: &mut Response) {
+        for v6 in &[false, true] {
+            for record in self.ip_records(hostname, self.ttl, *v6, interface) {
+                response.add_additional(record);
+            }
+        }
+        self.add_host_nsec(hostname, interface, response);
+    }
+
+    /// Address records of one family, none before the host name is
+    /// confirmed
+    fn ip_records(&self, hostname: &Name<'static>, ttl: u32, v6: bool, interface: Option<u32>) -> Vec<Record> {
+        if !self.confirmed.contains(&ProbeTarget::Host) {
+            return Vec::new();
+        }
+
+        self.ip_rdata(v6, interface).iter()
+            .map(|data| Record::new(hostname, ttl, data))
+            .collect()
+    }
+
+    /// Sends the records of `svc` on every interface, along with the
+    /// addresses of that interface if `include_ip` is set
+    fn send_unsolicited(&mut self, svc: &ServiceData, ttl: u32, include_ip: bool) {
+        for interface in self.multicast_targets() {
+            let mut response = Response::new();
+
+            {
+                let services = self.services.read().unwrap();
+                let hostname = services.get_hostname();
+
+                response.add_answer(svc.ptr_record(ttl));
+                response.add_answer(svc.srv_record(hostname, ttl));
+                response.add_answer(svc.txt_record(ttl));
+                if include_ip {
+                    for record in self.ip_records(hostname, ttl, AF::v6(), interface) {
+                        response.add_answer(record);
+                    }
+                }
+            }
+
+            let addr = self.group;
+            self.send_response(0, &response, addr, interface);
+        }
+    }
+
+    fn send_host_unsolicited(&mut self, ttl: u32) {
+        for interface in self.multicast_targets() {
+            let mut response = Response::new();
+
+            {
+                let services = self.services.read().unwrap();
+                for record in self.ip_records(services.get_hostname(), ttl, AF::v6(), interface) {
+                    response.add_answer(record);
+                }
+            }
+
+            let addr = self.group;
+            self.send_response(0, &response, addr, interface);
+        }
+    }
+
+    /// The interfaces multicast without a specific interface goes out on
+    ///
+    /// Where the interface can't be chosen, a copy for each would all leave
+    /// on the default one.
+    fn multicast_targets(&self) -> Vec<Option<u32>> {
+        if self.default_interface || !net::PER_INTERFACE_MULTICAST {
+            vec![None]
+        } else {
+            self.interfaces.iter().map(|iface| Some(iface.index())).collect()
+        }
+    }
+
+    /// The interfaces multicast queued for `interface` goes out on
+    fn send_targets(&self, interface: Option<u32>) -> Vec<Option<u32>> {
+        match interface {
+            Some(_) => vec![interface],
+            None => self.multicast_targets(),
+        }
+    }
+
+    fn send_response(&mut self, id: u16, response: &Response, addr: SocketAddr, interface: Option<u32>) {
+        if response.is_empty() {
+            return;
+        }
+
+        if addr.ip().is_multicast() {
+            let now = Instant::now();
+            let targets = self.send_targets(interface);
+            self.last_multicast.retain(|(iface, sent, _)| {
+                !targets.contains(iface) || !response.records().any(|record| sent.same_data(record))
+            });
+            for &target in &targets {
+                self.last_multicast.extend(response.records().map(|record| (target, record.clone(), now)));
+            }
+        }
+
+        for part in response.split(self.max_packet_size) {
+            let mut builder = dns_parser::Builder::new_response(id, false, true).move_to::<dns_parser::Answers>();
+            builder.set_max_size(None);
+            for record in &part.answers {
+                builder = record.add_answer(builder);
+            }
+            let mut builder = builder.move_to::<dns_parser::Additional>();
+            for record in &part.additional {
+                builder = record.add_additional(builder);
+            }
+
+            self.send_packet(builder.build().unwrap_or_else(|x| x), addr, interface);
+        }
+    }
+
+    fn send_packet(&mut self, response: Vec<u8>, addr: SocketAddr, interface: Option<u32>) {
+        if log::max_level() == log::LevelFilter::Trace {
+            use dns_parser::Packet;
+            let kind = if addr.ip().is_multicast() { "multicast" } else { "unicast" };
+            match Packet::parse(&response) {
+                Ok(packet) => {
+                    trace!("Sending {} {}", kind, packet);
+                }
+                Err(e) => {
+                    error!("Error parsing outgoing {} packet {:?}", kind, e);
+                }
+            }
+        }
+        self.queue_packet(response, addr, interface);
+    }
+
+    /// Queues a packet for sending; multicast for no particular interface
+    /// goes out on all of them
+    fn queue_packet(&mut self, packet: Vec<u8>, addr: SocketAddr, interface: Option<u32>) {
+        if addr.ip().is_multicast() && interface.is_none() {
+            for interface in self.multicast_targets() {
+                self.outgoing.push_back((packet.clone(), addr, interface));
+            }
+        } else {
+            self.outgoing.push_back((packet, addr, interface));
+        }
+    }
+
+    /// Whether `svc` was probed successfully under its current name
+    fn is_confirmed(&self, services: &ServicesInner, svc: &ServiceData) -> bool {
+        services.find_id_by_name(&svc.name)
+            .is_some_and(|id| self.confirmed.contains(&ProbeTarget::Service(id)))
+    }
+
+    /// The services we announced and answer for
+    fn confirmed_services(&self) -> Vec<ServiceData> {
+        let services = self.services.read().unwrap();
+        services.iter()
+            .filter(|svc| self.is_confirmed(&services, svc))
+            .cloned()
+            .collect()
+    }
+
+    /// The name currently claimed by `target`, `None` if it's gone
+    fn probe_name(&self, target: ProbeTarget) -> Option<Name<'static>> {
+        let services = self.services.read().unwrap();
+        match target {
+            ProbeTarget::Host => Some(services.get_hostname().clone()),
+            ProbeTarget::Service(id) => services.find_by_id(id).map(|svc| svc.name.clone()),
+        }
+    }
+
+    fn start_probe(&mut self, target: ProbeTarget) {
+        self.confirmed.remove(&target);
+        let name = match self.probe_name(target) {
+            Some(name) => name,
+            None => return,
+        };
+
+        // RFC 6762 §8.1: the first probe is delayed by a random 0-250ms
+        let delay = thread_rng().gen_range(0, PROBE_INTERVAL);
+        self.probes.retain(|probe| probe.target != target);
+        self.probes.push(Probe {
+            target,
+            name,
+            sent: 0,
+            next: Instant::now() + Duration::from_millis(delay),
+        });
+    }
+
+    fn handle_probes(&mut self, now: Instant) {
+        let probes = mem::take(&mut self.probes);
+        let mut succeeded = Vec::new();
+        for mut probe in probes {
+            if probe.next > now {
+                self.probes.push(probe);
                 continue;
             }
 
-            match iface.ip() {
-                Some(IpAddr::V4(ip)) if !AF::v6() => {
-                    builder = builder.add_answer(hostname, QueryClass::IN, ttl, &RRData::A(ip))
+            let name = match self.probe_name(probe.target) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if name != probe.name {
+                // renamed after a conflict seen by another address family
+                probe.name = name;
+                probe.sent = 0;
+            }
+
+            if probe.sent < PROBE_COUNT {
+                self.send_probe(&probe);
+                probe.sent += 1;
+                probe.next = now + Duration::from_millis(PROBE_INTERVAL);
+                self.probes.push(probe);
+            } else {
+                debug!("probing for {} succeeded", probe.name);
+                succeeded.push(probe.target);
+            }
+        }
+
+        // only announce once all of them are confirmed, so that the host
+        // announcement includes the SRV records of services that made it
+        // at the same time
+        self.confirmed.extend(succeeded.iter().cloned());
+        for target in succeeded {
+            self.start_announcements(target);
+        }
+    }
+
+    /// Starts announcing `target` after it was probed successfully or its
+    /// records changed (RFC 6762 §8.3)
+    ///
+    /// The first announcement goes out right away, the rest follow one,
+    /// two, four... seconds later.
+    fn start_announcements(&mut self, target: ProbeTarget) {
+        self.announcements.retain(|announcement| announcement.target != target);
+        if self.announce(target) && self.announcement_count > 1 {
+            self.announcements.push(Announcement {
+                target,
+                remaining: self.announcement_count - 1,
+                interval: ANNOUNCE_INTERVAL,
+                next: Instant::now() + Duration::from_millis(ANNOUNCE_INTERVAL),
+            });
+        }
+    }
+
+    fn handle_announcements(&mut self, now: Instant) {
+        let announcements = mem::take(&mut self.announcements);
+        for mut announcement in announcements {
+            if announcement.next > now {
+                self.announcements.push(announcement);
+                continue;
+            }
+
+            if !self.announce(announcement.target) {
+                continue;
+            }
+
+            announcement.remaining -= 1;
+            if announcement.remaining > 0 {
+                announcement.interval *= 2;
+                announcement.next = now + Duration::from_millis(announcement.interval);
+                self.announcements.push(announcement);
+            }
+        }
+    }
+
+    /// Sends an unsolicited response for `target`, returns `false` if there
+    /// is nothing to announce anymore
+    fn announce(&mut self, target: ProbeTarget) -> bool {
+        match target {
+            ProbeTarget::Host => {
+                if !self.confirmed.contains(&target) {
+                    return false;
+                }
+
+                self.send_host_unsolicited(self.ttl);
+
+                // SRV records announced so far may point to an old host name
+                for svc in self.confirmed_services() {
+                    self.send_unsolicited(&svc, self.ttl, false);
                 }
-                Some(IpAddr::V6(ip)) if AF::v6() => {
-                    builder = builder.add_answer(hostname, QueryClass::IN, ttl, &RRData::AAAA(ip))
+            }
+            ProbeTarget::Service(id) => {
+                let svc = match self.services.read().unwrap().find_by_id(id) {
+                    Some(svc) => svc.clone(),
+                    None => return false,
+                };
+                if !self.confirmed.contains(&target) {
+                    return false;
                 }
-                _ => ()
+
+                self.send_unsolicited(&svc, self.ttl, true);
             }
         }
 
-        builder
+        true
     }
 
-    fn send_unsolicited(&mut self, svc: &ServiceData, ttl: u32, include_ip: bool) {
-        let mut builder = dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+    /// Records we intend to claim under the probed name
+    fn proposed_rdata<'a>(&self, services: &'a ServicesInner, target: ProbeTarget) -> Vec<RRData<'a>> {
+        match target {
+            ProbeTarget::Host => self.ip_rdata(AF::v6(), None),
+            ProbeTarget::Service(id) => match services.find_by_id(id) {
+                Some(svc) => vec![svc.srv_rdata(services.get_hostname()), svc.txt_rdata()],
+                None => Vec::new(),
+            },
+        }
+    }
+
+    fn send_probe(&mut self, probe: &Probe) {
+        let mut builder = dns_parser::Builder::new_query(0, false)
+            .add_question(&probe.name, QueryType::All, QueryClass::IN)
+            .move_to::<dns_parser::Nameservers>();
         builder.set_max_size(None);
 
-        let services = self.services.read().unwrap();
+        {
+            let services = self.services.read().unwrap();
+            for data in self.proposed_rdata(&services, probe.target) {
+                builder = builder.add_nameserver(&probe.name, QueryClass::IN, self.ttl, &data);
+            }
+        }
+
+        let packet = builder.build().unwrap_or_else(|x| x);
+        let addr = self.group;
+        self.queue_packet(packet, addr, None);
+    }
+
+    /// Simultaneous probe tiebreaking (RFC 6762 §8.2)
+    ///
+    /// When another host probes for a name we are probing, the host whose
+    /// proposed records are lexicographically later wins. The loser waits
+    /// one second and probes again, by which time the winner will answer
+    /// and the usual conflict resolution takes over.
+    fn handle_probe_query(&mut self, packet: &dns_parser::Packet, addr: SocketAddr) {
+        if packet.nameservers.is_empty() {
+            return;
+        }
+
+        let mut lost = Vec::new();
+        {
+            let services = self.services.read().unwrap();
+
+            for probe in &self.probes {
+                let theirs: Vec<ProbeRecord> = packet.nameservers.iter()
+                    .filter(|rr| rr.name == probe.name)
+                    .map(|rr| ProbeRecord::new(rr.cls as u16, &rr.data))
+                    .collect();
+                if theirs.is_empty() {
+                    continue;
+                }
+
+                let ours = self.proposed_rdata(&services, probe.target).iter()
+                    .map(|data| ProbeRecord::new(QueryClass::IN as u16, data))
+                    .collect();
+
+                match compare_proposed(ours, theirs) {
+                    Ordering::Less => {
+                        info!("lost probe tiebreak for {} against {:?}, deferring", probe.name, addr);
+                        lost.push(probe.target);
+                    }
+                    Ordering::Equal => {
+                        // our own probe looped back
+                    }
+                    Ordering::Greater => {
+                        debug!("won probe tiebreak for {} against {:?}", probe.name, addr);
+                    }
+                }
+            }
+        }
+
+        let next = Instant::now() + Duration::from_millis(PROBE_DEFER);
+        for probe in self.probes.iter_mut().filter(|probe| lost.contains(&probe.target)) {
+            probe.sent = 0;
+            probe.next = next;
+        }
+    }
+
+    fn handle_response(&mut self, packet: &dns_parser::Packet, addr: SocketAddr, interface: Option<u32>) {
+        // RFC 6762 §7.4: answers someone else just multicast on the same
+        // link with at least our TTL don't need to be sent again
+        for queued in self.answers.iter_mut().filter(|queued| queued.interface == interface) {
+            queued.response.retain(|record| {
+                !packet.answers.iter().any(|rr| record.matches(rr) && rr.ttl >= record.ttl)
+            });
+        }
+
+        let mut conflicts = Vec::new();
+
+        {
+            let services = self.services.read().unwrap();
+            let hostname = services.get_hostname();
+
+            for rr in &packet.answers {
+                // Goodbye records (TTL 0) don't claim anything
+                if rr.ttl == 0 {
+                    continue;
+                }
+
+                // Any record for a name we are probing means someone else
+                // already owns it.
+                if let Some(probe) = self.probes.iter().find(|probe| probe.name == rr.name) {
+                    conflicts.push((probe.target, probe.name.clone()));
+                    continue;
+                }
+
+                // For names we already own, only unique records with data
+                // different from ours are a conflict. Identical data is
+                // most likely our own announcement looped back.
+                if rr.name == *hostname {
+                    let ip = match rr.data {
+                        RRData::A(ip) => IpAddr::V4(ip),
+                        RRData::AAAA(ip) => IpAddr::V6(ip),
+                        _ => continue,
+                    };
+                    if !self.ifaces.iter().any(|iface| iface.ip() == Some(ip)) {
+                        conflicts.push((ProbeTarget::Host, hostname.clone()));
+                    }
+                    continue;
+                }
+
+                let svc = match services.find_by_name(&rr.name) {
+                    Some(svc) => svc,
+                    None => continue,
+                };
+                let ours = match rr.data {
+                    RRData::SRV { .. } => svc.srv_rdata(hostname),
+                    RRData::TXT(..) => svc.txt_rdata(),
+                    _ => continue,
+                };
+                if rdata_bytes(&ours) != rdata_bytes(&rr.data) {
+                    let id = services.find_id_by_name(&rr.name).unwrap();
+                    conflicts.push((ProbeTarget::Service(id), svc.name.clone()));
+                }
+            }
+        }
+
+        conflicts.sort_by_key(|&(target, _)| target);
+        conflicts.dedup_by_key(|&mut (target, _)| target);
+        for (target, name) in conflicts {
+            warn!("name {} is already in use by {:?}", name, addr);
+            self.resolve_conflict(target, &name);
+        }
+    }
+
+    /// Renames `target` which conflicts under `name` and probes for the
+    /// new name
+    fn resolve_conflict(&mut self, target: ProbeTarget, name: &Name) {
+        let renamed = match target {
+            ProbeTarget::Host => Some(self.services.write().unwrap().rename_host(name)),
+            ProbeTarget::Service(id) => self.services.write().unwrap().rename(id, name),
+        };
+        match renamed {
+            Some(new_name) => {
+                info!("renaming {} to {}", name, new_name);
+                self.handle_name_changes();
+            }
+            None => {
+                self.probes.retain(|probe| probe.target != target);
+                self.confirmed.remove(&target);
+            }
+        }
+    }
+
+    /// Follows the renames after conflicts, whichever address family saw
+    /// them
+    ///
+    /// A name still being probed is probed under its new name by
+    /// `handle_probes`. A confirmed one gets goodbyes under the old name
+    /// and is probed again.
+    fn handle_name_changes(&mut self) {
+        while let Ok(Async::Ready(Some(change))) = self.name_changes.poll() {
+            let (target, old) = match change {
+                NameChange::Host { old } => (ProbeTarget::Host, old),
+                NameChange::Service { id, old } => (ProbeTarget::Service(id), old),
+            };
+            if !self.confirmed.contains(&target) {
+                continue;
+            }
+
+            debug!("{} was renamed, probing again", old);
+            match target {
+                ProbeTarget::Host => self.send_host_rename_goodbyes(&old),
+                ProbeTarget::Service(id) => self.send_rename_goodbyes(id, &old),
+            }
+            self.start_probe(target);
+        }
+    }
+
+    /// Sends goodbyes for the records of service `id` announced under its
+    /// old name
+    fn send_rename_goodbyes(&mut self, id: usize, old: &Name<'static>) {
+        let svc = match self.services.read().unwrap().find_by_id(id) {
+            Some(svc) => ServiceData { name: old.clone(), ..svc.clone() },
+            None => return,
+        };
+        self.send_unsolicited(&svc, 0, false);
+    }
+
+    /// Sends goodbyes for the addresses announced under the old host name
+    /// `old`, and for the SRV records pointing to it
+    fn send_host_rename_goodbyes(&mut self, old: &Name<'static>) {
+        for interface in self.multicast_targets() {
+            let mut response = Response::new();
+
+            {
+                let services = self.services.read().unwrap();
+                for record in self.ip_records(old, 0, AF::v6(), interface) {
+                    response.add_answer(record);
+                }
+                for svc in services.iter().filter(|svc| self.is_confirmed(&services, svc)) {
+                    response.add_answer(svc.srv_record(old, 0));
+                }
+            }
+
+            let addr = self.group;
+            self.send_response(0, &response, addr, interface);
+        }
+    }
+
+    /// Stops answering and queues goodbye packets (RFC 6762 §10.1) for
+    /// everything we announced. The task finishes once they are sent.
+    fn shutdown(&mut self) {
+        info!("shutting down");
+        self.shutting_down = true;
+
+        for svc in self.confirmed_services() {
+            self.send_unsolicited(&svc, 0, false);
+        }
+        self.send_host_unsolicited(0);
+
+        self.probes.clear();
+        self.confirmed.clear();
+        self.announcements.clear();
+        self.pending.clear();
+        self.answers.clear();
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let probes = self.probes.iter().map(|probe| probe.next);
+        let announcements = self.announcements.iter().map(|announcement| announcement.next);
+        let pending = self.pending.iter().map(|query| query.deadline);
+        let answers = self.answers.iter().map(|queued| queued.due);
+        probes.chain(announcements).chain(pending).chain(answers).min()
+    }
+
+    fn poll_timer(&mut self) -> io::Result<()> {
+        loop {
+            let now = Instant::now();
+            self.handle_probes(now);
+            self.handle_announcements(now);
+            self.handle_pending(now);
+            self.handle_answers(now);
+
+            match self.next_deadline() {
+                Some(deadline) => self.timer.reset(deadline),
+                None => return Ok(()),
+            }
+
+            if let Async::NotReady = self.timer.poll().map_err(into_io_error)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A record from the Authority section of a probe, with fields in the order
+/// RFC 6762 §8.2 compares them: class, type, then the raw uncompressed rdata
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ProbeRecord {
+    class: u16,
+    typ: u16,
+    rdata: Vec<u8>,
+}
+
+impl ProbeRecord {
+    fn new(class: u16, data: &RRData) -> ProbeRecord {
+        ProbeRecord {
+            class,
+            typ: data.typ() as u16,
+            rdata: rdata_bytes(data),
+        }
+    }
+}
+
+/// Compares the records we propose for a name with those of another prober
+///
+/// Both sets are sorted and compared record by record; if one set runs
+/// out first the other one is later. `Ordering::Less` means we lose.
+fn compare_proposed(mut ours: Vec<ProbeRecord>, mut theirs: Vec<ProbeRecord>) -> Ordering {
+    ours.sort();
+    theirs.sort();
+    ours.cmp(&theirs)
+}
 
-        builder = svc.add_ptr_rr(builder, ttl);
-        builder = svc.add_srv_rr(services.get_hostname(), builder, ttl);
-        builder = svc.add_txt_rr(builder, ttl);
-        if include_ip {
-            builder = self.add_ip_rr(services.get_hostname(), builder, ttl);
+/// Usable interfaces the filter allows with an address of the family, one
+/// per interface index
+fn multicast_interfaces<AF: AddressFamily>(ifaces: &[net::Interface], filter: &InterfaceFilter)
+    -> Vec<net::Interface>
+{
+    let mut interfaces: Vec<net::Interface> = Vec::new();
+    for iface in ifaces {
+        if !is_usable(iface) {
+            continue;
+        }
+        match iface.ip() {
+            Some(ip) if ip.is_ipv6() == AF::v6() => (),
+            _ => continue,
         }
+        if !filter.is_allowed(iface.name(), iface.index()) ||
+            interfaces.iter().any(|other| other.index() == iface.index())
+        {
+            continue;
+        }
+        interfaces.push(iface.clone());
+    }
+    interfaces
+}
 
-        if !builder.is_empty() {
-            let response = builder.build().unwrap_or_else(|x| x);
-            let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
-            self.outgoing.push_back((response, addr));
+/// The interface a packet from `addr` most likely arrived on: the one in
+/// the scope of a link-local source, or whose subnet contains the source
+fn source_interface(ifaces: &[net::Interface], addr: SocketAddr) -> Option<u32> {
+    if let SocketAddr::V6(addr) = addr {
+        if addr.scope_id() != 0 {
+            return Some(addr.scope_id());
         }
     }
+
+    ifaces.iter()
+        .filter(|iface| !iface.is_loopback())
+        .find(|iface| iface.contains(addr.ip()))
+        .map(|iface| iface.index())
+}
+
+/// Whether mDNS can be used on an interface: it's up and running, not the
+/// loopback and supports multicast
+fn is_usable(iface: &net::Interface) -> bool {
+    iface.is_up() && iface.is_running() && !iface.is_loopback() && iface.is_multicast()
+}
+
+fn ip_to_rdata(ip: IpAddr) -> RRData<'static> {
+    match ip {
+        IpAddr::V4(ip) => RRData::A(ip),
+        IpAddr::V6(ip) => RRData::AAAA(ip),
+    }
+}
+
+/// The in-addr.arpa or ip6.arpa name mapping `ip` back to a host name
+fn reverse_name(ip: IpAddr) -> Name<'static> {
+    let name = match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", octets[3], octets[2], octets[1], octets[0])
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    };
+    Name::from_str(name).unwrap()
+}
+
+fn is_reverse_name(name: &Name) -> bool {
+    let name = name.to_string().to_lowercase();
+    name.ends_with(".in-addr.arpa") || name.ends_with(".ip6.arpa")
 }
 
 impl <AF: AddressFamily> Future for FSM<AF> {
     type Item = ();
     type Error = io::Error;
     fn poll(&mut self) -> Poll<(), io::Error> {
-        while let Async::Ready(cmd) = self.commands.poll().unwrap() {
+        while !self.shutting_down {
+            let cmd = match self.commands.poll().unwrap() {
+                Async::Ready(cmd) => cmd,
+                Async::NotReady => break,
+            };
             match cmd {
-                Some(Command::Shutdown) => return Ok(Async::Ready(())),
-                Some(Command::SendUnsolicited { svc, ttl, include_ip }) => {
-                    self.send_unsolicited(&svc, ttl, include_ip);
+                Some(Command::Shutdown) => {
+                    self.shutdown();
+                }
+                Some(Command::SendUnsolicited { id, svc, ttl, include_ip }) => {
+                    let target = ProbeTarget::Service(id);
+                    if ttl == 0 && !self.confirmed.remove(&target) {
+                        // never announced, nothing to say goodbye to
+                        self.probes.retain(|probe| probe.target != target);
+                    } else {
+                        self.send_unsolicited(&svc, ttl, include_ip);
+                    }
+                }
+                Some(Command::Probe { id }) => {
+                    self.start_probe(ProbeTarget::Service(id));
+                }
+                Some(Command::SetAnnouncements { count }) => {
+                    self.announcement_count = count;
+                }
+                Some(Command::SetRateLimit { interval }) => {
+                    self.rate_limit = interval;
+                }
+                Some(Command::SetMaxPacketSize { size }) => {
+                    self.max_packet_size = size;
+                }
+                Some(Command::SetInterfaces { filter }) => {
+                    self.filter = filter;
+                    self.handle_address_changes(false);
                 }
                 None => {
                     warn!("responder disconnected without shutdown");
-                    return Ok(Async::Ready(()));
+                    self.shutdown();
                 }
             }
         }
 
-        let mut buf = [0u8; 4096];
-        while let Async::Ready((bytes, addr)) = self.socket.poll_recv_from(&mut buf)? {
-            if bytes >= buf.len() {
-                warn!("buffer too small for packet from {:?}", addr);
-                continue;
+        if !self.shutting_down {
+            self.handle_name_changes();
+
+            // room for anything that fits a jumbo frame
+            let mut buf = [0u8; 9000];
+            while let Async::Ready(_) = self.socket.poll_read_ready(mio::Ready::readable())? {
+                let (bytes, addr, interface) = match net::recv_from(self.socket.get_ref(), &mut buf) {
+                    Ok(received) => received,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        self.socket.clear_read_ready(mio::Ready::readable())?;
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                };
+                if bytes >= buf.len() {
+                    warn!("buffer too small for packet from {:?}", addr);
+                    continue;
+                }
+                self.handle_packet(&buf[..bytes], addr, interface);
+            }
+
+            let mut changes = Vec::new();
+            let mut failed = false;
+            if let Some(ref watcher) = self.watcher {
+                while let Async::Ready(_) = watcher.poll_read_ready(mio::Ready::readable())? {
+                    match watcher.get_ref().recv() {
+                        Ok(received) => changes.extend(received),
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            watcher.clear_read_ready(mio::Ready::readable())?;
+                            break;
+                        }
+                        Err(err) => {
+                            warn!("stopped watching address changes: {:?}", err);
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+            }
+            if failed {
+                self.watcher = None;
+            }
+            if !changes.is_empty() {
+                self.handle_address_changes(changes.contains(&net::Change::Link));
             }
-            self.handle_packet(&buf[..bytes], addr);
+
+            self.poll_timer()?;
         }
 
         // non-lexical borrow checker is required for while let loop
         #[allow(clippy::while_let_loop)]
         loop {
-            if let Some(&(ref response, ref addr)) = self.outgoing.front() {
-                trace!("sending packet to {:?}", addr);
+            if let Some(&(ref response, ref addr, interface)) = self.outgoing.front() {
+                trace!("sending packet to {:?} on interface {:?}", addr, interface);
+
+                if addr.ip().is_multicast() {
+                    // by index, goodbyes go out on interfaces already left
+                    if let Err(err) = AF::set_multicast_if(self.socket.get_ref(), interface) {
+                        warn!("cannot select interface {:?}: {:?}", interface, err);
+                    }
+                }
 
-                match self.socket.poll_send_to(response, addr) {
-                    Ok(Async::Ready(_)) => (),
-                    Ok(Async::NotReady) => break,
+                if let Async::NotReady = self.socket.poll_write_ready()? {
+                    break;
+                }
+                match self.socket.get_ref().send_to(response, addr) {
+                    Ok(_) => (),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        self.socket.clear_write_ready()?;
+                        break;
+                    }
                     Err(err) => warn!("error sending packet {:?}", err),
                 }
             } else {
@@ -259,6 +1565,389 @@ impl <AF: AddressFamily> Future for FSM<AF> {
             self.outgoing.pop_front();
         }
 
+        if self.shutting_down && self.outgoing.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use address_family::Inet;
+    use dns_parser::{Builder, Class, Packet};
+    use futures::future;
+    use services::ServicesInner;
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, RwLock};
+
+    /// An IPv4 FSM that answers for `count` confirmed services of type
+    /// `_test._tcp.local`, each with `txt_size` bytes of TXT data
+    ///
+    /// It uses an ephemeral socket and doesn't join the mDNS group, outgoing
+    /// packets are only queued. No interface addresses are known to it.
+    fn test_fsm(count: usize, txt_size: usize) -> FSM<Inet> {
+        let services = Arc::new(RwLock::new(ServicesInner::new("test.local".to_owned())));
+        for i in 0..count {
+            services.write().unwrap().register(ServiceData {
+                name: Name::from_str(format!("svc{}._test._tcp.local", i)).unwrap(),
+                typ: Name::from_str("_test._tcp.local").unwrap(),
+                port: 80,
+                txt: vec![b'x'; txt_size],
+            });
+        }
+        let config = Config {
+            ttl: 120,
+            group: "224.0.0.251:5353".parse().unwrap(),
+            announcements: 2,
+            filter: InterfaceFilter::new(),
+        };
+        let socket = UdpSocket::bind(&"0.0.0.0:0".parse().unwrap()).unwrap();
+        let socket = PollEvented2::new_with_handle(socket, &Handle::default()).unwrap();
+        let (mut fsm, _) = FSM::with_socket(socket, None, &services, &config);
+        fsm.default_interface = true;
+        fsm.ifaces.clear();
+        fsm.confirmed.insert(ProbeTarget::Host);
+        fsm.confirmed.extend(services.read().unwrap().ids().map(|&id| ProbeTarget::Service(id)));
+        fsm
+    }
+
+    fn query(id: u16, name: &str, qtype: QueryType) -> Vec<u8> {
+        Builder::new_query(id, false)
+            .add_question(&Name::from_str(name.to_owned()).unwrap(), qtype, QueryClass::IN)
+            .build().unwrap()
+    }
+
+    /// The raw class field of every record after the questions, including
+    /// the cache-flush bit the parser drops
+    fn record_classes(buffer: &[u8]) -> Vec<u16> {
+        fn skip_name(buffer: &[u8], mut offset: usize) -> usize {
+            loop {
+                match buffer[offset] {
+                    0 => return offset + 1,
+                    len if len & 0xc0 == 0xc0 => return offset + 2,
+                    len => offset += 1 + len as usize,
+                }
+            }
+        }
+        let header = dns_parser::Header::parse(buffer).unwrap();
+        let mut offset = 12;
+        for _ in 0..header.questions {
+            offset = skip_name(buffer, offset) + 4;
+        }
+        let records = header.answers + header.nameservers + header.additional;
+        (0..records).map(|_| {
+            offset = skip_name(buffer, offset);
+            let class = u16::from(buffer[offset + 2]) << 8 | u16::from(buffer[offset + 3]);
+            let len = u16::from(buffer[offset + 8]) << 8 | u16::from(buffer[offset + 9]);
+            offset += 10 + len as usize;
+            class
+        }).collect()
+    }
+
+    /// Not on any local subnet, so the FSM can't tell the interface
+    fn querier(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7)), port)
+    }
+
+    fn a(ip: [u8; 4]) -> ProbeRecord {
+        ProbeRecord::new(QueryClass::IN as u16, &RRData::A(Ipv4Addr::from(ip)))
+    }
+
+    #[test]
+    fn reverse_names() {
+        let v4 = reverse_name(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)));
+        assert_eq!(v4.to_string(), "20.1.168.192.in-addr.arpa");
+        assert!(is_reverse_name(&v4));
+
+        let v6 = reverse_name("2001:db8::567:89ab".parse().unwrap());
+        assert_eq!(v6.to_string(),
+                   "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa");
+        assert!(is_reverse_name(&v6));
+
+        assert!(!is_reverse_name(&Name::from_str("host.local").unwrap()));
+    }
+
+    #[test]
+    fn tiebreak_by_rdata() {
+        assert_eq!(compare_proposed(vec![a([169, 254, 99, 200])], vec![a([169, 254, 200, 50])]), Ordering::Less);
+        assert_eq!(compare_proposed(vec![a([169, 254, 200, 50])], vec![a([169, 254, 99, 200])]), Ordering::Greater);
+        assert_eq!(compare_proposed(vec![a([10, 0, 0, 1])], vec![a([10, 0, 0, 1])]), Ordering::Equal);
+    }
+
+    #[test]
+    fn tiebreak_by_type() {
+        let txt = ProbeRecord::new(QueryClass::IN as u16, &RRData::TXT(b"\x00"));
+        // TXT (16) is later than A (1) regardless of the data
+        assert_eq!(compare_proposed(vec![a([255, 255, 255, 255])], vec![txt]), Ordering::Less);
+    }
+
+    #[test]
+    fn tiebreak_sets() {
+        // order within the set doesn't matter
+        assert_eq!(compare_proposed(vec![a([10, 0, 0, 2]), a([10, 0, 0, 1])],
+                                    vec![a([10, 0, 0, 1]), a([10, 0, 0, 2])]),
+                   Ordering::Equal);
+        // the first differing record decides
+        assert_eq!(compare_proposed(vec![a([10, 0, 0, 1]), a([10, 0, 0, 9])],
+                                    vec![a([10, 0, 0, 1]), a([10, 0, 0, 3])]),
+                   Ordering::Greater);
+        // with a common prefix the longer set wins
+        assert_eq!(compare_proposed(vec![a([10, 0, 0, 1])],
+                                    vec![a([10, 0, 0, 1]), a([10, 0, 0, 2])]),
+                   Ordering::Less);
+    }
+
+    #[test]
+    fn legacy_unicast() {
+        let mut fsm = test_fsm(2, 8);
+        let addr = querier(40000);
+        fsm.handle_packet(&query(0x1234, "_test._tcp.local", QueryType::PTR), addr, None);
+
+        let (buffer, to, _) = fsm.outgoing.pop_front().unwrap();
+        assert_eq!(to, addr);
+        let packet = Packet::parse(&buffer).unwrap();
+        assert_eq!(packet.header.id, 0x1234);
+        assert!(!packet.header.query);
+        assert!(!packet.header.truncated);
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(packet.questions[0].qname.to_string(), "_test._tcp.local");
+        assert_eq!(packet.questions[0].qtype, QueryType::PTR);
+        assert_eq!(packet.answers.len(), 2);
+        assert!(!packet.additional.is_empty());
+        for record in packet.answers.iter().chain(&packet.additional) {
+            assert!(record.ttl <= LEGACY_TTL);
+            assert_eq!(record.cls, Class::IN);
+        }
+        // SRV and TXT are unique records, but legacy resolvers don't know
+        // the cache-flush bit
+        assert!(record_classes(&buffer).iter().all(|&class| class == QueryClass::IN as u16));
+        assert!(fsm.answers.is_empty());
+    }
+
+    #[test]
+    fn legacy_unicast_size() {
+        // the answers fit, the additional records don't
+        let mut fsm = test_fsm(4, 100);
+        fsm.handle_packet(&query(1, "_test._tcp.local", QueryType::PTR), querier(40000), None);
+        let (buffer, _, _) = fsm.outgoing.pop_front().unwrap();
+        assert!(buffer.len() <= LEGACY_PACKET_SIZE);
+        let packet = Packet::parse(&buffer).unwrap();
+        assert!(!packet.header.truncated);
+        assert_eq!(packet.answers.len(), 4);
+
+        // not even the answers fit
+        let mut fsm = test_fsm(12, 0);
+        fsm.handle_packet(&query(2, "_test._tcp.local", QueryType::PTR), querier(40000), None);
+        let (buffer, _, _) = fsm.outgoing.pop_front().unwrap();
+        assert!(buffer.len() <= LEGACY_PACKET_SIZE);
+        let packet = Packet::parse(&buffer).unwrap();
+        assert!(packet.header.truncated);
+        assert_eq!(packet.header.id, 2);
+    }
+
+    #[test]
+    fn renamed_elsewhere() {
+        // polling the name changes needs a task
+        future::lazy(|| {
+            let mut fsm = test_fsm(1, 0);
+            let id = *fsm.services.read().unwrap().ids().next().unwrap();
+            let host = fsm.services.read().unwrap().get_hostname().clone();
+            let svc_name = fsm.services.read().unwrap().find_by_id(id).unwrap().name.clone();
+
+            // another address family lost the host name, the established SRV
+            // record pointing to it is withdrawn
+            fsm.services.write().unwrap().rename_host(&host);
+            fsm.handle_name_changes();
+            assert!(fsm.probes.iter().any(|probe| probe.target == ProbeTarget::Host));
+            let goodbyes: Vec<_> = fsm.outgoing.drain(..).map(|(buffer, _, _)| buffer).collect();
+            assert!(!goodbyes.is_empty());
+            for buffer in &goodbyes {
+                let packet = Packet::parse(buffer).unwrap();
+                assert!(packet.answers.iter().all(|rr| rr.ttl == 0));
+                assert!(packet.answers.iter().any(|rr| match rr.data {
+                    RRData::SRV { ref target, .. } => rr.name == svc_name && *target == host,
+                    _ => false,
+                }));
+            }
+
+            // and then the service name
+            fsm.services.write().unwrap().rename(id, &svc_name);
+            fsm.handle_name_changes();
+            assert!(fsm.probes.iter().any(|probe| probe.target == ProbeTarget::Service(id)));
+            let goodbyes: Vec<_> = fsm.outgoing.drain(..).map(|(buffer, _, _)| buffer).collect();
+            assert!(!goodbyes.is_empty());
+            for buffer in &goodbyes {
+                let packet = Packet::parse(buffer).unwrap();
+                assert!(packet.answers.iter().all(|rr| rr.ttl == 0));
+                assert!(packet.answers.iter().any(|rr| match rr.data {
+                    RRData::PTR(ref name) => *name == svc_name,
+                    _ => false,
+                }));
+            }
+
+            // names still being probed are left to `handle_probes`
+            let svc_name = fsm.services.read().unwrap().find_by_id(id).unwrap().name.clone();
+            fsm.services.write().unwrap().rename(id, &svc_name);
+            fsm.handle_name_changes();
+            assert!(fsm.outgoing.is_empty());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn unconfirmed_services_hidden() {
+        let name = Name::from_str("new._test._tcp.local").unwrap();
+        // registered, but not probed by the FSM yet
+        let register = |fsm: &FSM<Inet>| {
+            fsm.services.write().unwrap().register(ServiceData {
+                name: name.clone(),
+                typ: Name::from_str("_test._tcp.local").unwrap(),
+                port: 80,
+                txt: vec![0],
+            })
+        };
+        let answered = |fsm: &mut FSM<Inet>| {
+            fsm.answers.clear();
+            fsm.handle_packet(&query(0, "_test._tcp.local", QueryType::PTR), querier(5353), Some(101));
+            fsm.handle_packet(&query(0, "new._test._tcp.local", QueryType::SRV), querier(5353), Some(101));
+            fsm.answers[0].response.records().any(|record| record.name == name)
+        };
+        let sent = |fsm: &mut FSM<Inet>| {
+            fsm.outgoing.drain(..).any(|(buffer, _, _)| {
+                Packet::parse(&buffer).unwrap().answers.iter().any(|rr| rr.name == name)
+            })
+        };
+
+        let mut fsm = test_fsm(1, 0);
+        let id = register(&fsm);
+        assert!(!answered(&mut fsm));
+        fsm.announce(ProbeTarget::Host);
+        assert!(!sent(&mut fsm));
+
+        fsm.confirmed.insert(ProbeTarget::Service(id));
+        assert!(answered(&mut fsm));
+        fsm.announce(ProbeTarget::Host);
+        assert!(sent(&mut fsm));
+
+        // no goodbyes for what was never announced
+        let mut fsm = test_fsm(1, 0);
+        register(&fsm);
+        fsm.shutdown();
+        assert!(!sent(&mut fsm));
+    }
+
+    #[test]
+    fn shared_answers_delayed() {
+        let mut fsm = test_fsm(1, 0);
+        let now = Instant::now();
+        fsm.handle_packet(&query(0, "_test._tcp.local", QueryType::PTR), querier(5353), Some(101));
+        assert!(fsm.outgoing.is_empty());
+        let due = fsm.answers[0].due;
+        assert!(due >= now + Duration::from_millis(ANSWER_DELAY_MIN));
+        assert!(due <= Instant::now() + Duration::from_millis(ANSWER_DELAY_MAX));
+
+        fsm.handle_answers(now + Duration::from_millis(ANSWER_DELAY_MIN / 2));
+        assert!(fsm.outgoing.is_empty());
+        fsm.handle_answers(now + Duration::from_millis(ANSWER_DELAY_MAX + 10));
+        assert!(fsm.answers.is_empty());
+        assert_eq!(fsm.outgoing.len(), 1);
+
+        // unique records only, no delay
+        let mut fsm = test_fsm(1, 0);
+        fsm.handle_packet(&query(0, "svc0._test._tcp.local", QueryType::SRV), querier(5353), Some(101));
+        assert!(fsm.answers[0].due <= Instant::now());
+    }
+
+    #[test]
+    fn answers_aggregated_per_interface() {
+        let mut fsm = test_fsm(1, 0);
+        let now = Instant::now();
+        let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 8)), 5353);
+        fsm.handle_packet(&query(0, "_test._tcp.local", QueryType::PTR), querier(5353), Some(101));
+        fsm.handle_packet(&query(0, "svc0._test._tcp.local", QueryType::TXT), other, Some(101));
+        fsm.handle_packet(&query(0, "_test._tcp.local", QueryType::PTR), other, Some(102));
+        assert_eq!(fsm.answers.len(), 2);
+        // the unique TXT answer brings the shared PTR answer forward
+        assert!(fsm.answers[0].due <= Instant::now());
+
+        fsm.handle_answers(now + Duration::from_millis(ANSWER_DELAY_MAX + 10));
+        let sent: Vec<_> = fsm.outgoing.drain(..).collect();
+        assert_eq!(sent.len(), 2);
+        for &(ref buffer, addr, interface) in &sent {
+            assert_eq!(addr, fsm.group);
+            let packet = Packet::parse(buffer).unwrap();
+            let types: Vec<Type> = packet.answers.iter().map(|rr| rr.data.typ()).collect();
+            if interface == Some(101) {
+                assert!(types.contains(&Type::PTR) && types.contains(&Type::TXT));
+            } else {
+                assert_eq!(interface, Some(102));
+                assert_eq!(types, vec![Type::PTR]);
+            }
+        }
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut fsm = test_fsm(1, 0);
+        let now = Instant::now();
+        let ptr = query(0, "_test._tcp.local", QueryType::PTR);
+        let answer = |fsm: &mut FSM<Inet>, interface, after| {
+            fsm.handle_packet(&ptr, querier(5353), interface);
+            fsm.handle_answers(now + Duration::from_millis(after));
+            fsm.outgoing.drain(..).count()
+        };
+
+        assert_eq!(answer(&mut fsm, Some(101), 200), 1);
+        // within a second of the last multicast on the interface
+        assert_eq!(answer(&mut fsm, Some(101), 600), 0);
+        // other interfaces don't count
+        assert_eq!(answer(&mut fsm, Some(102), 800), 1);
+        assert_eq!(answer(&mut fsm, Some(101), 1400), 1);
+
+        fsm.rate_limit = Duration::from_millis(0);
+        assert_eq!(answer(&mut fsm, Some(101), 1600), 1);
+
+        // queries from an unknown interface are limited by where their
+        // answers went out
+        let mut fsm = test_fsm(1, 0);
+        assert_eq!(answer(&mut fsm, None, 200), 1);
+        assert_eq!(answer(&mut fsm, None, 600), 0);
+    }
+
+    #[test]
+    fn truncated_queries_held() {
+        let mut truncated = query(0, "_test._tcp.local", QueryType::PTR);
+        dns_parser::Header::set_truncated(&mut truncated[..12]);
+        let known_answers = Builder::new_query(0, false)
+            .move_to::<dns_parser::Answers>()
+            .add_answer(&Name::from_str("_test._tcp.local").unwrap(), QueryClass::IN, false, 120,
+                        &RRData::PTR(Name::from_str("svc0._test._tcp.local").unwrap()))
+            .build().unwrap();
+
+        // answered once the known answers stop coming, without the ones
+        // listed in the later packet
+        let mut fsm = test_fsm(2, 0);
+        fsm.handle_packet(&truncated, querier(5353), Some(101));
+        assert_eq!(fsm.pending.len(), 1);
+        assert!(fsm.answers.is_empty());
+        fsm.handle_packet(&known_answers, querier(5353), Some(101));
+        assert!(fsm.pending.is_empty());
+        let answers: Vec<Vec<u8>> = fsm.answers[0].response.answers.iter()
+            .map(|record| record.rdata.clone())
+            .collect();
+        assert_eq!(answers, vec![rdata_bytes(&RRData::PTR(Name::from_str("svc1._test._tcp.local").unwrap()))]);
+
+        // or after 400-500ms if they never do
+        let mut fsm = test_fsm(1, 0);
+        let now = Instant::now();
+        fsm.handle_packet(&truncated, querier(5353), Some(101));
+        fsm.handle_pending(now + Duration::from_millis(KNOWN_ANSWER_WAIT / 2));
+        assert!(fsm.answers.is_empty());
+        fsm.handle_pending(now + Duration::from_millis(KNOWN_ANSWER_WAIT + 200));
+        assert!(fsm.pending.is_empty());
+        assert_eq!(fsm.answers.len(), 1);
+    }
+}
@@ -0,0 +1,117 @@
+/// Selects an interface by name or by index
+///
+/// Names may contain `*` wildcards matching any number of characters, so
+/// `"docker*"` selects every Docker bridge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InterfaceSelector {
+    Name(String),
+    Index(u32),
+}
+
+impl InterfaceSelector {
+    fn matches(&self, name: &str, index: u32) -> bool {
+        match *self {
+            InterfaceSelector::Name(ref pattern) => glob_match(pattern.as_bytes(), name.as_bytes()),
+            InterfaceSelector::Index(selected) => selected == index,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for InterfaceSelector {
+    fn from(name: &'a str) -> InterfaceSelector {
+        InterfaceSelector::Name(name.to_owned())
+    }
+}
+
+impl From<String> for InterfaceSelector {
+    fn from(name: String) -> InterfaceSelector {
+        InterfaceSelector::Name(name)
+    }
+}
+
+impl From<u32> for InterfaceSelector {
+    fn from(index: u32) -> InterfaceSelector {
+        InterfaceSelector::Index(index)
+    }
+}
+
+/// Which interfaces the responder joins the mDNS group on and advertises
+/// the addresses of
+///
+/// An interface is used if it matches one of the allowed selectors, or if
+/// there are none, and matches none of the denied ones. Loopback interfaces
+/// and those without multicast are never used.
+///
+/// For example `InterfaceFilter::new().deny("docker*").deny("veth*")` keeps
+/// the responder off container networks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceFilter {
+    allow: Vec<InterfaceSelector>,
+    deny: Vec<InterfaceSelector>,
+}
+
+impl InterfaceFilter {
+    /// A filter allowing every interface
+    pub fn new() -> InterfaceFilter {
+        InterfaceFilter::default()
+    }
+
+    /// Only uses the interfaces matching `selector` or another allowed one
+    pub fn allow<S: Into<InterfaceSelector>>(mut self, selector: S) -> InterfaceFilter {
+        self.allow.push(selector.into());
+        self
+    }
+
+    /// Never uses the interfaces matching `selector`
+    pub fn deny<S: Into<InterfaceSelector>>(mut self, selector: S) -> InterfaceFilter {
+        self.deny.push(selector.into());
+        self
+    }
+
+    /// Whether the interface called `name` with index `index` may be used
+    pub fn is_allowed(&self, name: &str, index: u32) -> bool {
+        let allowed = self.allow.is_empty() ||
+            self.allow.iter().any(|selector| selector.matches(name, index));
+        allowed && !self.deny.iter().any(|selector| selector.matches(name, index))
+    }
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_match(rest, &name[skip..])),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(glob_match(b"docker*", b"docker0"));
+        assert!(glob_match(b"docker*", b"docker"));
+        assert!(glob_match(b"*eth*", b"veth1234"));
+        assert!(glob_match(b"eth0", b"eth0"));
+        assert!(!glob_match(b"eth0", b"eth01"));
+        assert!(!glob_match(b"tun*", b"eth0"));
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let filter = InterfaceFilter::new();
+        assert!(filter.is_allowed("eth0", 2));
+
+        let filter = InterfaceFilter::new().deny("docker*").deny(7);
+        assert!(filter.is_allowed("eth0", 2));
+        assert!(!filter.is_allowed("docker0", 3));
+        assert!(!filter.is_allowed("wlan0", 7));
+
+        let filter = InterfaceFilter::new().allow("eth*").allow(5).deny("eth1");
+        assert!(filter.is_allowed("eth0", 2));
+        assert!(filter.is_allowed("wlan0", 5));
+        assert!(!filter.is_allowed("eth1", 3));
+        assert!(!filter.is_allowed("wlan1", 4));
+    }
+}
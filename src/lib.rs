@@ -5,17 +5,21 @@
 extern crate byteorder;
 extern crate futures;
 extern crate libc;
+extern crate mio;
 extern crate multimap;
 extern crate net2;
 extern crate nix;
 extern crate rand;
 extern crate tokio;
 
-use futures::Future;
-use futures::sync::mpsc;
+use futures::{future, Future};
+use futures::sync::{mpsc, oneshot};
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::cell::RefCell;
+use std::time::Duration;
 use tokio::reactor::Handle;
 
 mod dns_parser;
@@ -23,6 +27,8 @@ use dns_parser::Name;
 
 mod address_family;
 mod fsm;
+mod interface_filter;
+mod record;
 mod services;
 #[cfg(windows)]
 #[path = "netwin.rs"]
@@ -30,71 +36,250 @@ mod net;
 #[cfg(not(windows))]
 mod net;
 
-use address_family::{Inet, Inet6};
+pub use interface_filter::{InterfaceFilter, InterfaceSelector};
+
+use address_family::{AddressFamily, Inet, Inet6};
 use services::{ServicesInner, Services, ServiceData};
-use fsm::{Command, FSM};
+use fsm::{Command, Config, FSM, MAX_ANNOUNCEMENTS, MAX_PACKET_SIZE};
 
 const DEFAULT_TTL : u32 = 60;
+const DEFAULT_ANNOUNCEMENTS : u8 = 2;
 const MDNS_PORT : u16 = 5353;
 
 pub struct Responder {
     services: Services,
     commands: RefCell<CommandSender>,
     shutdown: Arc<Shutdown>,
+    finished: oneshot::Receiver<()>,
 }
 
 pub struct Service {
     id: usize,
     services: Services,
     commands: CommandSender,
-    _shutdown: Arc<Shutdown>,
+    shutdown: Arc<Shutdown>,
 }
 
 type ResponderTask = Box<dyn Future<Item=(), Error=io::Error> + Send>;
+type ShutdownFuture = Box<dyn Future<Item=(), Error=io::Error> + Send>;
 
 impl Responder {
     pub fn new() -> io::Result<(Responder, ResponderTask)> {
-        Responder::with_handle(&Handle::default())
+        ResponderBuilder::new().build()
     }
 
     pub fn with_handle(handle: &Handle) -> io::Result<(Responder, ResponderTask)> {
-        info!("Responder::with_handle()");
-        let mut hostname = net::gethostname()?;
+        ResponderBuilder::new().handle(handle).build()
+    }
+
+    /// Returns a builder for a responder with non-default settings
+    pub fn builder() -> ResponderBuilder {
+        ResponderBuilder::new()
+    }
+}
+
+/// Configures a `Responder` before it's started
+///
+/// Settings not changed keep the defaults of `Responder::new`: the system
+/// host name, a TTL of 60 seconds, both address families on the standard
+/// mDNS port and groups, every interface, two announcements and the
+/// default reactor.
+#[derive(Clone, Debug)]
+pub struct ResponderBuilder {
+    hostname: Option<String>,
+    ttl: u32,
+    ipv4: bool,
+    ipv6: bool,
+    port: u16,
+    group_v4: Ipv4Addr,
+    group_v6: Ipv6Addr,
+    interfaces: InterfaceFilter,
+    announcements: u8,
+    handle: Option<Handle>,
+}
+
+impl Default for ResponderBuilder {
+    fn default() -> ResponderBuilder {
+        ResponderBuilder::new()
+    }
+}
+
+impl ResponderBuilder {
+    pub fn new() -> ResponderBuilder {
+        let group_v4 = match Inet::mdns_group() {
+            IpAddr::V4(group) => group,
+            IpAddr::V6(_) => unreachable!(),
+        };
+        let group_v6 = match Inet6::mdns_group() {
+            IpAddr::V6(group) => group,
+            IpAddr::V4(_) => unreachable!(),
+        };
+
+        ResponderBuilder {
+            hostname: None,
+            ttl: DEFAULT_TTL,
+            ipv4: true,
+            ipv6: true,
+            port: MDNS_PORT,
+            group_v4,
+            group_v6,
+            interfaces: InterfaceFilter::new(),
+            announcements: DEFAULT_ANNOUNCEMENTS,
+            handle: None,
+        }
+    }
+
+    /// Sets the host name, `.local` is appended if missing
+    ///
+    /// Labels may be at most 63 bytes long, `build` fails otherwise.
+    pub fn hostname<S: Into<String>>(mut self, hostname: S) -> ResponderBuilder {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Sets the TTL of our records in seconds, it must not be zero
+    pub fn ttl(mut self, ttl: u32) -> ResponderBuilder {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Enables or disables the IPv4 responder
+    pub fn ipv4(mut self, enabled: bool) -> ResponderBuilder {
+        self.ipv4 = enabled;
+        self
+    }
+
+    /// Enables or disables the IPv6 responder, which is optional: failing
+    /// to start it is only logged while IPv4 is enabled as well
+    pub fn ipv6(mut self, enabled: bool) -> ResponderBuilder {
+        self.ipv6 = enabled;
+        self
+    }
+
+    /// Sets the UDP port listened and sent to instead of 5353
+    ///
+    /// Queries from any other port are answered as legacy unicast queries.
+    pub fn port(mut self, port: u16) -> ResponderBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Sets the IPv4 multicast group instead of 224.0.0.251
+    pub fn group_v4(mut self, group: Ipv4Addr) -> ResponderBuilder {
+        self.group_v4 = group;
+        self
+    }
+
+    /// Sets the IPv6 multicast group instead of ff02::fb
+    pub fn group_v6(mut self, group: Ipv6Addr) -> ResponderBuilder {
+        self.group_v6 = group;
+        self
+    }
+
+    /// Restricts the interfaces used from the start, see
+    /// `Responder::set_interfaces`
+    pub fn interfaces(mut self, filter: InterfaceFilter) -> ResponderBuilder {
+        self.interfaces = filter;
+        self
+    }
+
+    /// Sets the number of announcements, see `Responder::set_announcements`
+    pub fn announcements(mut self, count: u8) -> ResponderBuilder {
+        self.announcements = count.clamp(2, MAX_ANNOUNCEMENTS);
+        self
+    }
+
+    /// Registers the sockets with `handle` instead of the default reactor
+    pub fn handle(mut self, handle: &Handle) -> ResponderBuilder {
+        self.handle = Some(handle.clone());
+        self
+    }
+
+    fn config(&self, group: IpAddr) -> Config {
+        Config {
+            ttl: self.ttl,
+            group: SocketAddr::new(group, self.port),
+            announcements: self.announcements,
+            filter: self.interfaces.clone(),
+        }
+    }
+
+    /// Starts the responder
+    ///
+    /// The returned task has to be spawned on the reactor's runtime for the
+    /// responder to do anything.
+    pub fn build(self) -> io::Result<(Responder, ResponderTask)> {
+        info!("ResponderBuilder::build()");
+        if !self.ipv4 && !self.ipv6 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address family enabled"));
+        }
+        if self.ttl == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "TTL must not be zero"));
+        }
+
+        let mut hostname = match self.hostname {
+            Some(ref hostname) => hostname.clone(),
+            None => net::gethostname()?,
+        };
         if !hostname.ends_with(".local") {
             hostname.push_str(".local");
         }
+        if let Err(err) = Name::from_str(hostname.clone()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("invalid host name {:?}: {}", hostname, err)));
+        }
 
+        let handle = self.handle.clone().unwrap_or_default();
         let services = Arc::new(RwLock::new(ServicesInner::new(hostname)));
 
-        info!("Creating V4 FSM");
-        let v4 = FSM::<Inet>::new(handle, &services);
-        info!("Creating V6 FSM");
-        let v6 = FSM::<Inet6>::new(handle, &services);
-
-        let (task, commands) : (ResponderTask, _) = match (v4, v6) {
-            (Ok((v4_task, v4_command)), Ok((v6_task, v6_command))) => {
-                let task = v4_task.join(v6_task).map(|((),())| ());
-                let task = Box::new(task);
-                let commands = vec![v4_command, v6_command];
-                (task, commands)
-            }
+        let mut tasks: Vec<ResponderTask> = Vec::new();
+        let mut commands = Vec::new();
 
-            (Ok((v4_task, v4_command)), Err(err)) => {
-                warn!("Failed to register IPv6 receiver: {:?}", err);
-                (Box::new(v4_task), vec![v4_command])
+        if self.ipv4 {
+            info!("Creating V4 FSM");
+            match FSM::<Inet>::new(&handle, &services, &self.config(IpAddr::V4(self.group_v4))) {
+                Ok((task, command)) => {
+                    tasks.push(Box::new(task));
+                    commands.push(command);
+                }
+                Err(err) => {
+                    error!("Failed to registed IPv4 receiver: {:?}", err);
+                    return Err(err);
+                }
             }
+        }
 
-            (Err(err), _) => {
-                error!("Failed to registed IPv4 receiver: {:?}", err);
-                return Err(err);
+        if self.ipv6 {
+            info!("Creating V6 FSM");
+            match FSM::<Inet6>::new(&handle, &services, &self.config(IpAddr::V6(self.group_v6))) {
+                Ok((task, command)) => {
+                    tasks.push(Box::new(task));
+                    commands.push(command);
+                }
+                Err(ref err) if self.ipv4 => {
+                    warn!("Failed to register IPv6 receiver: {:?}", err);
+                }
+                Err(err) => {
+                    error!("Failed to register IPv6 receiver: {:?}", err);
+                    return Err(err);
+                }
             }
-        };
+        }
+
+        // By the time the task resolves the FSMs, and with them the
+        // sockets, have been dropped
+        let (finished_tx, finished) = oneshot::channel();
+        let task = Box::new(future::join_all(tasks).then(move |result| {
+            let _ = finished_tx.send(());
+            result.map(|_| ())
+        }));
 
         let commands = CommandSender(commands);
         let responder = Responder {
             services: services,
             commands: RefCell::new(commands.clone()),
-            shutdown: Arc::new(Shutdown(commands)),
+            shutdown: Arc::new(Shutdown::new(commands)),
+            finished,
         };
 
         Ok((responder, task))
@@ -131,20 +316,103 @@ impl Responder {
             txt: txt,
         };
 
-        self.commands.borrow_mut()
-            .send_unsolicited(svc.clone(), DEFAULT_TTL, true);
-
         let id = self.services
             .write().unwrap()
             .register(svc);
 
+        self.commands.borrow_mut()
+            .send_probe(id);
+
         Ok(Service {
             id: id,
             commands: self.commands.borrow().clone(),
             services: self.services.clone(),
-            _shutdown: self.shutdown.clone(),
+            shutdown: self.shutdown.clone(),
         })
     }
+
+    /// Stops the responder, regardless of any `Service` still registered
+    ///
+    /// Goodbye packets are sent for all services and the host name. The
+    /// returned future completes once the responder task has finished and
+    /// released its sockets, so a new responder can be started right after.
+    pub fn shutdown(self) -> ShutdownFuture {
+        self.shutdown.send();
+        Box::new(self.finished.map_err(|_| {
+            into_io_error("responder task was dropped before finishing")
+        }))
+    }
+}
+
+impl Service {
+    /// Returns the instance name the service is currently advertised under
+    ///
+    /// This differs from the name passed to `Responder::register` when the
+    /// service was renamed because the name was already taken on the
+    /// network.
+    pub fn name(&self) -> String {
+        self.services
+            .read().unwrap()
+            .find_by_id(self.id)
+            .expect("unknown service")
+            .instance()
+    }
+
+    /// Returns a stream of the new instance names, one for every time the
+    /// service is renamed because of a name conflict
+    pub fn renames(&self) -> mpsc::UnboundedReceiver<String> {
+        self.services
+            .write().unwrap()
+            .subscribe_renames(self.id)
+    }
+}
+
+impl Responder {
+    /// Sets how many unsolicited responses are sent when a service or the
+    /// host name has been claimed
+    ///
+    /// RFC 6762 requires between two (the default) and eight announcements,
+    /// `count` is clamped to that range. The interval between them starts
+    /// at one second and doubles every time.
+    pub fn set_announcements(&self, count: u8) {
+        let count = count.clamp(2, MAX_ANNOUNCEMENTS);
+        self.commands.borrow_mut()
+            .send(Command::SetAnnouncements { count });
+    }
+
+    /// Sets the minimum interval between two multicasts of the same record
+    /// in answer to queries
+    ///
+    /// RFC 6762 asks for at most one per second, which is the default.
+    /// Answers to probe queries, announcements and goodbyes are not limited.
+    /// A zero interval disables the limit.
+    pub fn set_rate_limit(&self, interval: Duration) {
+        self.commands.borrow_mut()
+            .send(Command::SetRateLimit { interval });
+    }
+
+    /// Sets the largest DNS message sent, bigger responses are split into
+    /// several packets
+    ///
+    /// The default of 1440 bytes fits a standard Ethernet frame. Networks
+    /// with jumbo frames may use up to 8952 bytes, `size` is clamped to
+    /// between 512 and that. Records of the same name and type are never split,
+    /// so a single huge RRset may still exceed the limit.
+    pub fn set_max_packet_size(&self, size: usize) {
+        let size = size.clamp(512, MAX_PACKET_SIZE);
+        self.commands.borrow_mut()
+            .send(Command::SetMaxPacketSize { size });
+    }
+
+    /// Restricts the interfaces the responder operates on
+    ///
+    /// The mDNS group is joined on the interfaces `filter` allows and left
+    /// on the others, and only their addresses are advertised. Queries
+    /// arriving on excluded interfaces are ignored.
+    pub fn set_interfaces(&self, filter: InterfaceFilter) {
+        self.commands.borrow_mut()
+            .send(Command::SetInterfaces { filter });
+    }
 }
 
 impl Drop for Service {
@@ -153,15 +421,40 @@ impl Drop for Service {
         let svc = self.services
             .write().unwrap()
             .unregister(self.id);
-        self.commands.send_unsolicited(svc, 0, false);
+        // after a shutdown the goodbye has been sent already
+        if !self.shutdown.is_sent() {
+            self.commands.send_unsolicited(self.id, svc, 0, false);
+        }
+    }
+}
+
+struct Shutdown {
+    commands: CommandSender,
+    sent: AtomicBool,
+}
+
+impl Shutdown {
+    fn new(commands: CommandSender) -> Shutdown {
+        Shutdown {
+            commands,
+            sent: AtomicBool::new(false),
+        }
+    }
+
+    fn send(&self) {
+        if !self.sent.swap(true, Ordering::SeqCst) {
+            self.commands.send_shutdown();
+        }
+    }
+
+    fn is_sent(&self) -> bool {
+        self.sent.load(Ordering::SeqCst)
     }
 }
 
-struct Shutdown(CommandSender);
 impl Drop for Shutdown {
     fn drop(&mut self) {
-        self.0.send_shutdown();
-        // TODO wait for tasks to shutdown
+        self.send();
     }
 }
 
@@ -169,23 +462,39 @@ impl Drop for Shutdown {
 struct CommandSender(Vec<mpsc::UnboundedSender<Command>>);
 
 impl CommandSender {
-    fn send(&mut self, cmd: Command) {
-        for tx in self.0.iter_mut() {
+    fn send(&self, cmd: Command) {
+        for tx in self.0.iter() {
             if let Err(e) = tx.unbounded_send(cmd.clone()) {
                 error!("CommandSender::send(): {:?}", e)
             }
         }
     }
 
-    fn send_unsolicited(&mut self, svc: ServiceData, ttl: u32, include_ip: bool) {
+    fn send_unsolicited(&mut self, id: usize, svc: ServiceData, ttl: u32, include_ip: bool) {
         self.send(Command::SendUnsolicited {
+            id,
             svc: svc,
             ttl: ttl,
             include_ip: include_ip,
         });
     }
 
-    fn send_shutdown(&mut self) {
+    fn send_probe(&mut self, id: usize) {
+        self.send(Command::Probe { id });
+    }
+
+    fn send_shutdown(&self) {
         self.send(Command::Shutdown);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invalid_hostname() {
+        let err = Responder::builder().hostname("x".repeat(64)).build().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
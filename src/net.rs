@@ -1,8 +1,16 @@
-use libc::{self, c_char, c_int, c_uint, size_t};
+use libc::{self, c_char, c_int, c_uint, c_void, size_t, socklen_t};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::net::UdpSocket;
+#[cfg(target_os = "linux")]
+use mio::unix::EventedFd;
+use std::ffi::CStr;
 use std::io;
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+use std::os::unix::io::AsRawFd;
 use std::ptr::null_mut;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-use byteorder::{BigEndian, ByteOrder};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 pub fn gethostname() -> io::Result<String> {
     let mut name = vec![0u8; 65];
@@ -58,59 +66,455 @@ impl Drop for GetIfAddrs {
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct Interface {
+    name: String,
+    index: u32,
     addr: Option<SocketAddr>,
+    netmask: Option<IpAddr>,
     flags: c_uint,
 }
 
 impl Interface {
     fn new(ifa: &libc::ifaddrs) -> Interface {
-        let addr = unsafe {
-            if ifa.ifa_addr.is_null() {
-                None
-            } else {
-                match (*ifa.ifa_addr).sa_family as c_int {
-                    libc::AF_INET => {
-                        let sa = *(ifa.ifa_addr as *const libc::sockaddr_in);
-                        let ip = IpAddr::V4(u32::from_be(sa.sin_addr.s_addr).into());
-                        let port = u16::from_be(sa.sin_port);
-                        Some(SocketAddr::new(ip, port))
-                    }
-                    libc::AF_INET6 => {
-                        let sa = *(ifa.ifa_addr as *const libc::sockaddr_in6);
-                        let addr = sa.sin6_addr.s6_addr;
-                        let ip = IpAddr::V6(Ipv6Addr::new(BigEndian::read_u16(&addr[0..2]),
-                                                          BigEndian::read_u16(&addr[2..4]),
-                                                          BigEndian::read_u16(&addr[4..6]),
-                                                          BigEndian::read_u16(&addr[6..8]),
-                                                          BigEndian::read_u16(&addr[8..10]),
-                                                          BigEndian::read_u16(&addr[10..12]),
-                                                          BigEndian::read_u16(&addr[12..14]),
-                                                          BigEndian::read_u16(&addr[14..16])));
-                        let port = u16::from_be(sa.sin6_port);
-                        Some(SocketAddr::new(ip, port))
-                    }
-                    _ => None,
-                }
-            }
-        };
+        let addr = unsafe { socket_addr(ifa.ifa_addr) };
+        let netmask = unsafe { socket_addr(ifa.ifa_netmask) };
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        let index = unsafe { libc::if_nametoindex(ifa.ifa_name) };
 
         Interface {
-            addr: addr,
+            name: name.to_string_lossy().into_owned(),
+            index,
+            addr,
+            netmask: netmask.map(|mask| mask.ip()),
             flags: ifa.ifa_flags,
         }
     }
 
-    #[allow(dead_code)]
-    pub fn addr(&self) -> Option<SocketAddr> {
-        self.addr
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The interface index, as reported by `recv_from`
+    pub fn index(&self) -> u32 {
+        self.index
     }
 
     pub fn ip(&self) -> Option<IpAddr> {
         self.addr.map(|a| a.ip())
     }
 
+    /// The scope id of an IPv6 address, 0 for IPv4
+    #[allow(dead_code)]
+    pub fn scope_id(&self) -> u32 {
+        match self.addr {
+            Some(SocketAddr::V6(addr)) => addr.scope_id(),
+            _ => 0,
+        }
+    }
+
+    pub fn netmask(&self) -> Option<IpAddr> {
+        self.netmask
+    }
+
+    /// The number of leading one bits in the netmask
+    pub fn prefix_len(&self) -> Option<u32> {
+        self.netmask().map(prefix_len)
+    }
+
+    /// Whether `ip` is in the subnet of the interface address
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip(), self.prefix_len()) {
+            (Some(own), Some(len)) => in_subnet(own, ip, len),
+            _ => false,
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        (self.flags as c_int & libc::IFF_UP) == libc::IFF_UP
+    }
+
+    pub fn is_running(&self) -> bool {
+        (self.flags as c_int & libc::IFF_RUNNING) == libc::IFF_RUNNING
+    }
+
     pub fn is_loopback(&self) -> bool {
         (self.flags as c_int & libc::IFF_LOOPBACK) == libc::IFF_LOOPBACK
     }
+
+    pub fn is_multicast(&self) -> bool {
+        (self.flags as c_int & libc::IFF_MULTICAST) == libc::IFF_MULTICAST
+    }
+}
+
+fn prefix_len(netmask: IpAddr) -> u32 {
+    match netmask {
+        IpAddr::V4(mask) => u32::from(mask).leading_ones(),
+        IpAddr::V6(mask) => u128::from(mask).leading_ones(),
+    }
+}
+
+/// Whether the first `len` bits of `a` and `b` are the same
+fn in_subnet(a: IpAddr, b: IpAddr, len: u32) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            u32::from(a) & mask == u32::from(b) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            u128::from(a) & mask == u128::from(b) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Converts an IPv4 or IPv6 `sockaddr`, `None` for other families
+unsafe fn socket_addr(sa: *const libc::sockaddr) -> Option<SocketAddr> {
+    if sa.is_null() {
+        return None;
+    }
+
+    match (*sa).sa_family as c_int {
+        libc::AF_INET => {
+            let sa = *(sa as *const libc::sockaddr_in);
+            let ip = Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sa.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sa = *(sa as *const libc::sockaddr_in6);
+            let ip = Ipv6Addr::from(sa.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sa.sin6_port),
+                                                  sa.sin6_flowinfo, sa.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
+
+/// Asks the kernel to report the interface each datagram arrives on
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_pktinfo(socket: &UdpSocket, v6: bool) -> io::Result<()> {
+    let (level, option) = if v6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_PKTINFO)
+    };
+    setsockopt(socket, level, option, &(1 as c_int))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_pktinfo(_socket: &UdpSocket, _v6: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether `set_multicast_if_v4` and `set_multicast_if_v6` pick the
+/// interface multicast goes out on
+pub const PER_INTERFACE_MULTICAST: bool = true;
+
+/// Selects the interface outgoing IPv4 multicast is sent on, by index, or
+/// lets the kernel pick it if `index` is 0
+///
+/// Unlike the address, the index still works once the interface has lost
+/// its address, when the goodbyes for it are sent.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_multicast_if_v4(socket: &UdpSocket, index: u32) -> io::Result<()> {
+    let mreqn = libc::ip_mreqn {
+        imr_multiaddr: libc::in_addr { s_addr: 0 },
+        imr_address: libc::in_addr { s_addr: 0 },
+        imr_ifindex: index as c_int,
+    };
+    setsockopt(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &mreqn)
+}
+
+/// Selects the interface outgoing IPv4 multicast is sent on, by index, or
+/// lets the kernel pick it if `index` is 0
+///
+/// The option only takes an address here, so an interface without an IPv4
+/// address can't be selected.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_multicast_if_v4(socket: &UdpSocket, index: u32) -> io::Result<()> {
+    let ip = if index == 0 {
+        Ipv4Addr::new(0, 0, 0, 0)
+    } else {
+        getifaddrs()
+            .filter(|iface| iface.index() == index)
+            .filter_map(|iface| match iface.ip() {
+                Some(IpAddr::V4(ip)) => Some(ip),
+                _ => None,
+            })
+            .next()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?
+    };
+    let addr = libc::in_addr { s_addr: u32::from(ip).to_be() };
+    setsockopt(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &addr)
+}
+
+/// Selects the interface outgoing IPv6 multicast is sent on, by index
+pub fn set_multicast_if_v6(socket: &UdpSocket, interface: u32) -> io::Result<()> {
+    setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_IF, &(interface as c_uint))
+}
+
+fn setsockopt<T>(socket: &UdpSocket, level: c_int, option: c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, option,
+                         value as *const T as *const c_void,
+                         mem::size_of::<T>() as socklen_t)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receives a datagram, also returning the index of the interface it
+/// arrived on if `set_pktinfo` is supported
+pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<u32>)> {
+    unsafe {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+        // big enough for either in_pktinfo or in6_pktinfo
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let bytes = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+        if bytes < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let addr = socket_addr(&addr as *const libc::sockaddr_storage as *const libc::sockaddr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))?;
+        Ok((bytes as usize, addr, pktinfo_index(&msg)))
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn pktinfo_index(msg: &libc::msghdr) -> Option<u32> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        let data = libc::CMSG_DATA(cmsg);
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = (data as *const libc::in_pktinfo).read_unaligned();
+                return Some(info.ipi_ifindex as u32);
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = (data as *const libc::in6_pktinfo).read_unaligned();
+                return Some(info.ipi6_ifindex as u32);
+            }
+            _ => (),
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn pktinfo_index(_msg: &libc::msghdr) -> Option<u32> {
+    None
+}
+
+/// A change reported by `AddressWatcher`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// An address was added or removed, or an interface went away
+    Address,
+    /// An interface went up or down
+    Link,
+}
+
+/// Listens for address and link changes on a netlink socket
+#[cfg(target_os = "linux")]
+pub struct AddressWatcher {
+    fd: RawFd,
+}
+
+#[cfg(target_os = "linux")]
+impl AddressWatcher {
+    pub fn new() -> io::Result<AddressWatcher> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                         libc::NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let watcher = AddressWatcher { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        let ret = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_nl>() as socklen_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(watcher)
+    }
+
+    /// Reads one batch of notifications
+    pub fn recv(&self) -> io::Result<Vec<Change>> {
+        let mut buf = [0u32; 2048];
+        let bytes = unsafe {
+            libc::recv(self.fd, buf.as_mut_ptr() as *mut c_void, mem::size_of_val(&buf), 0)
+        };
+        if bytes < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOBUFS) {
+                // notifications were lost, the caller has to look again
+                return Ok(vec![Change::Address]);
+            }
+            return Err(err);
+        }
+
+        let buf = unsafe { ::std::slice::from_raw_parts(buf.as_ptr() as *const u8, bytes as usize) };
+        Ok(parse_netlink(buf))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn parse_netlink(mut buf: &[u8]) -> Vec<Change> {
+    let header_len = mem::size_of::<libc::nlmsghdr>();
+    let mut changes = Vec::new();
+    while buf.len() >= header_len {
+        let header = unsafe { (buf.as_ptr() as *const libc::nlmsghdr).read_unaligned() };
+        let len = header.nlmsg_len as usize;
+        if len < header_len || len > buf.len() {
+            break;
+        }
+
+        match header.nlmsg_type {
+            libc::RTM_NEWADDR | libc::RTM_DELADDR | libc::RTM_DELLINK => changes.push(Change::Address),
+            libc::RTM_NEWLINK => {
+                // struct ifinfomsg, only changes of these flags matter
+                let msg = &buf[header_len..len];
+                if msg.len() >= 16 {
+                    let change = u32::from_ne_bytes([msg[12], msg[13], msg[14], msg[15]]);
+                    if change & (libc::IFF_UP | libc::IFF_RUNNING) as u32 != 0 {
+                        changes.push(Change::Link);
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        let aligned = (len + 3) & !3;
+        buf = &buf[aligned.min(buf.len())..];
+    }
+    changes
+}
+
+#[cfg(target_os = "linux")]
+impl Evented for AddressWatcher {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for AddressWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Address changes aren't watched on this platform, `new` always fails
+#[cfg(not(target_os = "linux"))]
+pub enum AddressWatcher {}
+
+#[cfg(not(target_os = "linux"))]
+impl AddressWatcher {
+    pub fn new() -> io::Result<AddressWatcher> {
+        Err(io::Error::new(io::ErrorKind::Other, "address changes are not supported on this platform"))
+    }
+
+    pub fn recv(&self) -> io::Result<Vec<Change>> {
+        match *self {}
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Evented for AddressWatcher {
+    fn register(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+        match *self {}
+    }
+
+    fn reregister(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+        match *self {}
+    }
+
+    fn deregister(&self, _: &Poll) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    fn message(typ: u16, payload: &[u8]) -> Vec<u8> {
+        let len = 16 + payload.len() as u32;
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&len.to_ne_bytes());
+        msg.extend_from_slice(&typ.to_ne_bytes());
+        msg.extend_from_slice(&[0; 10]);
+        msg.extend_from_slice(payload);
+        while msg.len() % 4 != 0 {
+            msg.push(0);
+        }
+        msg
+    }
+
+    #[cfg(target_os = "linux")]
+    fn ifinfomsg(change: u32) -> Vec<u8> {
+        let mut msg = vec![0; 12];
+        msg.extend_from_slice(&change.to_ne_bytes());
+        msg
+    }
+
+    #[test]
+    fn subnets() {
+        assert_eq!(prefix_len("255.255.255.0".parse().unwrap()), 24);
+        assert_eq!(prefix_len("ffff:ffff:ffff:ffff::".parse().unwrap()), 64);
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(in_subnet(ip("192.168.1.10"), ip("192.168.1.200"), 24));
+        assert!(!in_subnet(ip("192.168.1.10"), ip("192.168.2.10"), 24));
+        assert!(in_subnet(ip("10.0.0.1"), ip("192.168.2.10"), 0));
+        assert!(in_subnet(ip("fd00::2"), ip("fd00::1:5"), 64));
+        assert!(!in_subnet(ip("fd00::2"), ip("fd01::2"), 64));
+        assert!(!in_subnet(ip("fd00::2"), ip("10.0.0.1"), 0));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn netlink_changes() {
+        let mut buf = message(libc::RTM_NEWADDR, &[0; 9]);
+        buf.extend(message(libc::RTM_NEWLINK, &ifinfomsg(0)));
+        buf.extend(message(libc::RTM_NEWLINK, &ifinfomsg(libc::IFF_RUNNING as u32)));
+        buf.extend(message(libc::RTM_DELADDR, &[0; 8]));
+        assert_eq!(parse_netlink(&buf), vec![Change::Address, Change::Link, Change::Address]);
+    }
 }
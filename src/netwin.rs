@@ -3,9 +3,12 @@ extern crate kernel32;
 extern crate socket2;
 
 use std;
+use std::ffi::CStr;
 use std::mem;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::io;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::net::UdpSocket;
 use self::winapi::{AF_UNSPEC, ERROR_SUCCESS, ERROR_BUFFER_OVERFLOW, ULONG, PVOID, DWORD, PCHAR};
 
 pub fn gethostname() -> io::Result<String> {
@@ -28,23 +31,109 @@ pub fn gethostname() -> io::Result<String> {
   Ok(String::from_utf8_lossy(&host).into_owned())
 }
 
-pub struct InterfaceAddress {
-  ip: Option<IpAddr>,
-  is_loopback: bool
+#[derive(Clone, Debug)]
+pub struct Interface {
+  name: String,
+  index: u32,
+  addr: Option<SocketAddr>,
+  prefix_len: Option<u32>,
+  is_loopback: bool,
+  is_multicast: bool,
+  is_up: bool
 }
 
-impl InterfaceAddress {
+impl Interface {
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn index(&self) -> u32 {
+    self.index
+  }
+
   pub fn ip(&self) -> Option<IpAddr> {
-    self.ip
+    self.addr.map(|a| a.ip())
+  }
+
+  #[allow(dead_code)]
+  pub fn scope_id(&self) -> u32 {
+    match self.addr {
+      Some(SocketAddr::V6(addr)) => addr.scope_id(),
+      _ => 0
+    }
+  }
+
+  // Built from the on-link prefix length, which is all Windows reports
+  pub fn netmask(&self) -> Option<IpAddr> {
+    match (self.addr, self.prefix_len) {
+      (Some(SocketAddr::V4(_)), Some(len)) =>
+        Some(IpAddr::V4(Ipv4Addr::from(u32::MAX.checked_shl(32u32.saturating_sub(len)).unwrap_or(0)))),
+      (Some(SocketAddr::V6(_)), Some(len)) =>
+        Some(IpAddr::V6(Ipv6Addr::from(u128::MAX.checked_shl(128u32.saturating_sub(len)).unwrap_or(0)))),
+      _ => None
+    }
   }
 
   pub fn is_loopback(&self) -> bool {
     self.is_loopback
   }
+
+  pub fn is_multicast(&self) -> bool {
+    self.is_multicast
+  }
+
+  pub fn is_up(&self) -> bool {
+    self.is_up
+  }
+
+  // Windows only reports the operational status, which covers both
+  pub fn is_running(&self) -> bool {
+    self.is_up
+  }
+
+  pub fn prefix_len(&self) -> Option<u32> {
+    self.prefix_len
+  }
+
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    match (self.ip(), self.netmask(), ip) {
+      (Some(IpAddr::V4(own)), Some(IpAddr::V4(mask)), IpAddr::V4(ip)) => {
+        let mask = u32::from(mask);
+        u32::from(own) & mask == u32::from(ip) & mask
+      }
+      (Some(IpAddr::V6(own)), Some(IpAddr::V6(mask)), IpAddr::V6(ip)) => {
+        let mask = u128::from(mask);
+        u128::from(own) & mask == u128::from(ip) & mask
+      }
+      _ => false
+    }
+  }
+}
+
+pub fn getifaddrs() -> ::std::vec::IntoIter<Interface> {
+   getifaddrs_int().unwrap().into_iter()
+}
+
+// The ingress interface is not reported on Windows, so queries are
+// answered with every address
+pub fn set_pktinfo(_socket: &UdpSocket, _v6: bool) -> io::Result<()> {
+  Ok(())
+}
+
+// Multicast goes out on the default interface only
+pub const PER_INTERFACE_MULTICAST: bool = false;
+
+pub fn set_multicast_if_v4(_socket: &UdpSocket, _interface: u32) -> io::Result<()> {
+  Ok(())
 }
 
-pub fn getifaddrs() -> Vec<InterfaceAddress> {
-   getifaddrs_int().unwrap()
+pub fn set_multicast_if_v6(_socket: &UdpSocket, _interface: u32) -> io::Result<()> {
+  Ok(())
+}
+
+pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<u32>)> {
+  let (bytes, addr) = socket.recv_from(buf)?;
+  Ok((bytes, addr, None))
 }
 
 #[repr(C)]
@@ -54,16 +143,29 @@ struct SOCKET_ADDRESS {
   length: winapi::c_int
 }
 
+// Copied from: https://msdn.microsoft.com/en-us/library/windows/desktop/aa366066(v=vs.85).aspx
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct IP_ADAPTER_UNICAST_ADDRESS {
   length: ULONG,
   flags: DWORD,
   next: *const IP_ADAPTER_UNICAST_ADDRESS,
-  address: SOCKET_ADDRESS
+  address: SOCKET_ADDRESS,
+  prefix_origin: winapi::c_int,
+  suffix_origin: winapi::c_int,
+  dad_state: winapi::c_int,
+  valid_lifetime: ULONG,
+  preferred_lifetime: ULONG,
+  lease_lifetime: ULONG,
+  on_link_prefix_length: u8
 }
 
+const IP_ADAPTER_NO_MULTICAST: ULONG = 0x0010;
+const IF_TYPE_SOFTWARE_LOOPBACK: ULONG = 24;
+const IF_OPER_STATUS_UP: ULONG = 1;
+
 // Copied from: https://msdn.microsoft.com/en-us/library/windows/desktop/aa366058(v=vs.85).aspx
+// up to OperStatus, the fields after it aren't used
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct IP_ADAPTER_ADDRESSES {
@@ -72,6 +174,18 @@ struct IP_ADAPTER_ADDRESSES {
   next: *const IP_ADAPTER_ADDRESSES,
   adapter_name: PCHAR,
   first_unicast_address: *const IP_ADAPTER_UNICAST_ADDRESS,
+  first_anycast_address: PVOID,
+  first_multicast_address: PVOID,
+  first_dns_server_address: PVOID,
+  dns_suffix: PVOID,
+  description: PVOID,
+  friendly_name: PVOID,
+  physical_address: [u8; 8],
+  physical_address_length: ULONG,
+  flags: ULONG,
+  mtu: ULONG,
+  if_type: ULONG,
+  oper_status: ULONG,
 }
 
 impl Default for IP_ADAPTER_ADDRESSES {
@@ -82,6 +196,18 @@ impl Default for IP_ADAPTER_ADDRESSES {
       next: std::ptr::null(),
       adapter_name: std::ptr::null_mut(),
       first_unicast_address: std::ptr::null(),
+      first_anycast_address: std::ptr::null_mut(),
+      first_multicast_address: std::ptr::null_mut(),
+      first_dns_server_address: std::ptr::null_mut(),
+      dns_suffix: std::ptr::null_mut(),
+      description: std::ptr::null_mut(),
+      friendly_name: std::ptr::null_mut(),
+      physical_address: [0; 8],
+      physical_address_length: 0,
+      flags: 0,
+      mtu: 0,
+      if_type: 0,
+      oper_status: 0,
     }
   }
 }
@@ -97,7 +223,7 @@ extern "system" {
     -> ULONG;
 }
 
-fn getifaddrs_int() -> io::Result<Vec<InterfaceAddress>> {
+fn getifaddrs_int() -> io::Result<Vec<Interface>> {
     let mut buf_len: ULONG = 0;
     let result =
       unsafe {
@@ -140,11 +266,18 @@ fn getifaddrs_int() -> io::Result<Vec<InterfaceAddress>> {
     let mut adapter_addresses_ptr = adapters_addresses_buffer.as_ptr();
     while adapter_addresses_ptr != std::ptr::null_mut() {
       let unicast_addresses = unsafe{ get_unicast_addresses((*adapter_addresses_ptr).first_unicast_address) }?;
+      let name = unsafe{ CStr::from_ptr((*adapter_addresses_ptr).adapter_name) }.to_string_lossy().into_owned();
+      let adapter = unsafe { *adapter_addresses_ptr };
 
-      for unicast_address in unicast_addresses.iter() {
-        ret.push(InterfaceAddress {
-          ip: Some(*unicast_address),
-          is_loopback: (*unicast_address).is_loopback()
+      for &(addr, prefix_len) in unicast_addresses.iter() {
+        ret.push(Interface {
+          name: name.clone(),
+          index: adapter.if_index,
+          addr: Some(addr),
+          prefix_len: Some(prefix_len),
+          is_loopback: adapter.if_type == IF_TYPE_SOFTWARE_LOOPBACK || addr.ip().is_loopback(),
+          is_multicast: adapter.flags & IP_ADAPTER_NO_MULTICAST == 0,
+          is_up: adapter.oper_status == IF_OPER_STATUS_UP
         });
       }
 
@@ -154,14 +287,15 @@ fn getifaddrs_int() -> io::Result<Vec<InterfaceAddress>> {
     Ok(ret)
 }
 
-unsafe fn get_unicast_addresses(unicast_addresses_ptr: *const IP_ADAPTER_UNICAST_ADDRESS) -> io::Result<Vec<IpAddr>> {
+// The addresses with their on-link prefix length
+unsafe fn get_unicast_addresses(unicast_addresses_ptr: *const IP_ADAPTER_UNICAST_ADDRESS) -> io::Result<Vec<(SocketAddr, u32)>> {
   let mut target_unicast_addresses = vec![];
 
   let mut unicast_address_ptr = unicast_addresses_ptr;
   while unicast_address_ptr != std::ptr::null_mut() {
     let socket_address = &(*unicast_address_ptr).address;
-    let ipaddr = socket_address_to_ipaddr(socket_address);
-    target_unicast_addresses.push(ipaddr);
+    let addr = socket_address_to_addr(socket_address);
+    target_unicast_addresses.push((addr, (*unicast_address_ptr).on_link_prefix_length as u32));
 
     unicast_address_ptr = (*unicast_address_ptr).next;
   }
@@ -169,10 +303,43 @@ unsafe fn get_unicast_addresses(unicast_addresses_ptr: *const IP_ADAPTER_UNICAST
   Ok(target_unicast_addresses)
 }
 
-unsafe fn socket_address_to_ipaddr(socket_address: &SOCKET_ADDRESS) -> IpAddr {
+unsafe fn socket_address_to_addr(socket_address: &SOCKET_ADDRESS) -> SocketAddr {
   let sockaddr = socket2::SockAddr::from_raw_parts(socket_address.lp_sockaddr as *const _, socket_address.length);
 
   sockaddr.as_inet()
-    .map(|s| IpAddr::V4(*s.ip()))
-    .unwrap_or_else(|| IpAddr::V6(*sockaddr.as_inet6().unwrap().ip()))
+    .map(SocketAddr::V4)
+    .unwrap_or_else(|| SocketAddr::V6(sockaddr.as_inet6().unwrap()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+  Address,
+  Link,
+}
+
+// Address changes aren't watched on Windows, `new` always fails
+pub enum AddressWatcher {}
+
+impl AddressWatcher {
+  pub fn new() -> io::Result<AddressWatcher> {
+    Err(io::Error::new(io::ErrorKind::Other, "address changes are not supported on this platform"))
+  }
+
+  pub fn recv(&self) -> io::Result<Vec<Change>> {
+    match *self {}
+  }
+}
+
+impl Evented for AddressWatcher {
+  fn register(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+    match *self {}
+  }
+
+  fn reregister(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+    match *self {}
+  }
+
+  fn deregister(&self, _: &Poll) -> io::Result<()> {
+    match *self {}
+  }
 }
@@ -0,0 +1,266 @@
+use dns_parser::{self, Name, QueryClass, RRData, ResourceRecord, Type};
+use std::mem;
+
+pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
+pub type AdditionalBuilder = dns_parser::Builder<dns_parser::Additional>;
+
+/// Size of the DNS header preceding the records
+const HEADER_SIZE: usize = 12;
+
+/// A resource record the responder is about to send
+///
+/// Unlike `RRData` the data is owned and already in wire format, so records
+/// can be compared with the ones received from other hosts and kept around
+/// until they are written into a packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub name: Name<'static>,
+    pub typ: Type,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+    /// Set for unique records, tells queriers to replace what they have
+    /// cached for the name and type (RFC 6762 §10.2)
+    pub cache_flush: bool,
+}
+
+impl Record {
+    /// A unique record, only we may have records of this name and type
+    pub fn new(name: &Name<'static>, ttl: u32, data: &RRData) -> Record {
+        Record {
+            name: name.clone(),
+            typ: data.typ(),
+            ttl,
+            rdata: rdata_bytes(data),
+            cache_flush: true,
+        }
+    }
+
+    /// A shared record, other responders may add to its RRset
+    pub fn shared(name: &Name<'static>, ttl: u32, data: &RRData) -> Record {
+        Record {
+            cache_flush: false,
+            ..Record::new(name, ttl, data)
+        }
+    }
+
+    /// Shared records may be owned by several responders at once, so
+    /// answers with them are delayed (RFC 6762 §6)
+    pub fn is_shared(&self) -> bool {
+        !self.cache_flush
+    }
+
+    /// Whether `other` is the same record, possibly with another TTL
+    pub fn same_data(&self, other: &Record) -> bool {
+        self.typ == other.typ && self.name == other.name && self.rdata == other.rdata
+    }
+
+    /// Whether `rr` carries the same data under the same name, regardless
+    /// of the TTL
+    pub fn matches(&self, rr: &ResourceRecord) -> bool {
+        rr.data.typ() == self.typ &&
+            rr.name == self.name &&
+            rdata_bytes(&rr.data) == self.rdata
+    }
+
+    /// Known-answer suppression (RFC 6762 §7.1)
+    ///
+    /// A record doesn't need to be sent when the querier listed it as a
+    /// known answer with at least half of our TTL remaining.
+    pub fn is_known(&self, known_answers: &[ResourceRecord]) -> bool {
+        known_answers.iter().any(|rr| {
+            self.matches(rr) && u64::from(rr.ttl) * 2 >= u64::from(self.ttl)
+        })
+    }
+
+    /// Size of the record in a packet, names aren't compressed
+    pub fn size(&self) -> usize {
+        let mut name = Vec::new();
+        self.name.write_to(&mut name).unwrap();
+        // type, class, TTL and data length
+        name.len() + 10 + self.rdata.len()
+    }
+
+    pub fn add_answer(&self, builder: AnswerBuilder) -> AnswerBuilder {
+        builder.add_answer(&self.name, QueryClass::IN, self.cache_flush, self.ttl, &self.data())
+    }
+
+    pub fn add_additional(&self, builder: AdditionalBuilder) -> AdditionalBuilder {
+        builder.add_additional(&self.name, QueryClass::IN, self.cache_flush, self.ttl, &self.data())
+    }
+
+    fn data(&self) -> RRData<'_> {
+        RRData::Unknown {
+            typ: self.typ,
+            data: &self.rdata,
+        }
+    }
+}
+
+/// Records for the Answer and Additional sections of a response
+///
+/// A record is only ever in one of the sections, answers take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Response {
+    pub answers: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+impl Response {
+    pub fn new() -> Response {
+        Response::default()
+    }
+
+    pub fn add_answer(&mut self, record: Record) {
+        self.additional.retain(|other| *other != record);
+        if !self.answers.contains(&record) {
+            self.answers.push(record);
+        }
+    }
+
+    pub fn add_additional(&mut self, record: Record) {
+        if !self.answers.contains(&record) && !self.additional.contains(&record) {
+            self.additional.push(record);
+        }
+    }
+
+    pub fn extend(&mut self, other: Response) {
+        for record in other.answers {
+            self.add_answer(record);
+        }
+        for record in other.additional {
+            self.add_additional(record);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.answers.is_empty() && self.additional.is_empty()
+    }
+
+    /// Size of a packet holding just these records
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.records().map(|record| record.size()).sum::<usize>()
+    }
+
+    pub fn records(&self) -> impl Iterator<Item=&Record> {
+        self.answers.iter().chain(&self.additional)
+    }
+
+    /// Keeps only the records in both sections for which `keep` is true
+    pub fn retain<F: FnMut(&Record) -> bool>(&mut self, mut keep: F) {
+        self.answers.retain(|record| keep(record));
+        self.additional.retain(|record| keep(record));
+    }
+
+    /// Splits the response into parts that fit into packets of `max_size`
+    /// bytes
+    ///
+    /// Records with the same name and type form an RRset which always stays
+    /// in one packet, even if that makes the packet too big. Answers are
+    /// placed ahead of additional records, otherwise the order is kept.
+    pub fn split(&self, max_size: usize) -> Vec<Response> {
+        let mut parts = Vec::new();
+        let mut part = Response::new();
+        let mut size = HEADER_SIZE;
+        let sections = rrsets(&self.answers).into_iter().map(|rrset| (rrset, true))
+            .chain(rrsets(&self.additional).into_iter().map(|rrset| (rrset, false)));
+        for (rrset, answer) in sections {
+            let rrset_size: usize = rrset.iter().map(|record| record.size()).sum();
+            if !part.is_empty() && size + rrset_size > max_size {
+                parts.push(mem::take(&mut part));
+                size = HEADER_SIZE;
+            }
+            size += rrset_size;
+            let section = if answer { &mut part.answers } else { &mut part.additional };
+            section.extend(rrset.into_iter().cloned());
+        }
+        if !part.is_empty() {
+            parts.push(part);
+        }
+        parts
+    }
+}
+
+/// Groups `records` by name and type, in order of first appearance
+fn rrsets(records: &[Record]) -> Vec<Vec<&Record>> {
+    let mut rrsets: Vec<Vec<&Record>> = Vec::new();
+    for record in records {
+        match rrsets.iter_mut().find(|rrset| rrset[0].name == record.name && rrset[0].typ == record.typ) {
+            Some(rrset) => rrset.push(record),
+            None => rrsets.push(vec![record]),
+        }
+    }
+    rrsets
+}
+
+/// Encodes `data` the way it appears in a packet, with names uncompressed
+pub fn rdata_bytes(data: &RRData) -> Vec<u8> {
+    let mut buf = Vec::new();
+    data.write_to(&mut buf).unwrap();
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dns_parser::{Builder, Class, Packet};
+    use std::net::Ipv4Addr;
+
+    fn known_answer(ttl: u32) -> Vec<u8> {
+        Builder::new_query(0, false)
+            .add_question(&Name::from_str("_http._tcp.local").unwrap(),
+                          dns_parser::QueryType::PTR, QueryClass::IN)
+            .add_answer(&Name::from_str("_http._tcp.local").unwrap(), QueryClass::IN, false, ttl,
+                        &RRData::PTR(Name::from_str("Web Server._http._tcp.local").unwrap()))
+            .build().unwrap()
+    }
+
+    #[test]
+    fn known_answer_suppression() {
+        let ptr = Record::shared(&Name::from_str("_http._tcp.local").unwrap(), 60,
+                              &RRData::PTR(Name::from_str("Web Server._http._tcp.local").unwrap()));
+
+        let query = known_answer(30);
+        let packet = Packet::parse(&query).unwrap();
+        assert_eq!(packet.answers[0].cls, Class::IN);
+        assert!(ptr.is_known(&packet.answers));
+
+        let query = known_answer(29);
+        let packet = Packet::parse(&query).unwrap();
+        assert!(!ptr.is_known(&packet.answers));
+
+        let a = Record::new(&Name::from_str("_http._tcp.local").unwrap(), 60,
+                            &RRData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        let query = known_answer(60);
+        let packet = Packet::parse(&query).unwrap();
+        assert!(!a.is_known(&packet.answers));
+    }
+
+    #[test]
+    fn split_keeps_rrsets_together() {
+        let host = Name::from_str("host.local").unwrap();
+        let a = |last| Record::new(&host, 60, &RRData::A(Ipv4Addr::new(10, 0, 0, last)));
+        let txt = Record::new(&Name::from_str("Web Server._http._tcp.local").unwrap(), 60,
+                              &RRData::TXT(b"\x06path=/"));
+        // each A record takes 12 bytes for the name, 10 for the fields and 4 of data
+        assert_eq!(a(1).size(), 26);
+
+        let mut response = Response::new();
+        response.add_answer(a(1));
+        response.add_additional(txt.clone());
+        response.add_answer(a(2));
+
+        let parts = response.split(12 + 26 * 2);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].answers, vec![a(1), a(2)]);
+        assert_eq!(parts[1].additional, vec![txt.clone()]);
+
+        let parts = response.split(1440);
+        assert_eq!(parts, vec![response.clone()]);
+
+        let mut response = Response::new();
+        response.add_additional(a(1));
+        response.add_answer(a(2));
+        response.add_answer(a(1));
+        assert_eq!(response.split(20), vec![Response { answers: vec![a(2), a(1)], additional: vec![] }]);
+    }
+}
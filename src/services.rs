@@ -1,12 +1,12 @@
 use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
+use std::mem;
 use std::slice;
+use futures::sync::mpsc;
 use multimap::MultiMap;
 use rand::{Rng, thread_rng};
-use dns_parser::{self, QueryClass, Name, RRData};
-
-pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
-
+use dns_parser::{Name, RRData, Type};
+use record::Record;
 
 /// A collection of registered services is shared between threads.
 pub type Services = Arc<RwLock<ServicesInner>>;
@@ -18,7 +18,18 @@ pub struct ServicesInner {
     /// maps to id
     by_type: MultiMap<Name<'static>, usize>,
     /// maps to id
-    by_name: HashMap<Name<'static>, usize>
+    by_name: HashMap<Name<'static>, usize>,
+    /// rename notifications, by id
+    renames: MultiMap<usize, mpsc::UnboundedSender<String>>,
+    /// notifications of every rename, for the FSMs
+    name_changes: Vec<mpsc::UnboundedSender<NameChange>>,
+}
+
+/// A name given up after a conflict, see `ServicesInner::subscribe_name_changes`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameChange {
+    Host { old: Name<'static> },
+    Service { id: usize, old: Name<'static> },
 }
 
 impl ServicesInner {
@@ -28,6 +39,8 @@ impl ServicesInner {
             by_id: HashMap::new(),
             by_type: MultiMap::new(),
             by_name: HashMap::new(),
+            renames: MultiMap::new(),
+            name_changes: Vec::new(),
         }
     }
 
@@ -35,11 +48,19 @@ impl ServicesInner {
         &self.hostname
     }
 
+    pub fn find_by_id(&self, id: usize) -> Option<&ServiceData> {
+        self.by_id.get(&id)
+    }
+
     pub fn find_by_name<'a>(&'a self, name: &'a Name<'a>) -> Option<&ServiceData> {
         self.by_name.get(name)
             .and_then(|id| self.by_id.get(id))
     }
 
+    pub fn find_id_by_name(&self, name: &Name) -> Option<usize> {
+        self.by_name.get(name).cloned()
+    }
+
     pub fn find_by_type<'a>(&'a self, ty: &'a Name<'a>) -> FindByType<'a> {
         let ids = self.by_type.get_vec(ty)
                               .map(|ids| ids.iter());
@@ -50,16 +71,29 @@ impl ServicesInner {
         }
     }
 
+    pub fn iter(&self) -> ::std::collections::hash_map::Values<'_, usize, ServiceData> {
+        self.by_id.values()
+    }
+
+    pub fn ids(&self) -> ::std::collections::hash_map::Keys<'_, usize, ServiceData> {
+        self.by_id.keys()
+    }
+
     pub fn types_iter(&self) -> ::std::collections::hash_map::Keys<Name<'static>, Vec<usize>> {
         self.by_type.keys()
     }
 
-    pub fn register(&mut self, svc: ServiceData) -> usize {
+    pub fn register(&mut self, mut svc: ServiceData) -> usize {
         let mut id = thread_rng().gen::<usize>();
         while self.by_id.contains_key(&id) {
             id = thread_rng().gen::<usize>();
         }
 
+        // Two local services can't share a name either
+        if self.by_name.contains_key(&svc.name) {
+            svc.name = self.unused_name(&svc);
+        }
+
         self.by_type.insert(svc.typ.clone(), id);
         self.by_name.insert(svc.name.clone(), id);
         self.by_id.insert(id, svc);
@@ -86,8 +120,135 @@ impl ServicesInner {
             }
         }
 
+        self.renames.remove(&id);
+
         svc
     }
+
+    /// Picks a new name for the service `id` after `conflicting` turned out
+    /// to be owned by someone else on the link.
+    ///
+    /// If the service is no longer called `conflicting` (the conflict was
+    /// already resolved through another interface) its current name is
+    /// returned unchanged.
+    pub fn rename(&mut self, id: usize, conflicting: &Name) -> Option<Name<'static>> {
+        let name = {
+            let svc = self.by_id.get(&id)?;
+            if svc.name != *conflicting {
+                return Some(svc.name.clone());
+            }
+            self.unused_name(svc)
+        };
+
+        let svc = self.by_id.get_mut(&id).unwrap();
+        self.by_name.remove(&svc.name);
+        self.by_name.insert(name.clone(), id);
+        let old = mem::replace(&mut svc.name, name.clone());
+
+        let instance = svc.instance();
+        if let Some(listeners) = self.renames.get_vec_mut(&id) {
+            listeners.retain(|tx| tx.unbounded_send(instance.clone()).is_ok());
+        }
+        self.notify_name_change(NameChange::Service { id, old });
+
+        Some(name)
+    }
+
+    /// Picks a new host name after `conflicting` turned out to be owned by
+    /// another host, e.g. "host.local" becomes "host-2.local"
+    ///
+    /// Like `rename`, nothing changes if the host was already renamed.
+    pub fn rename_host(&mut self, conflicting: &Name) -> Name<'static> {
+        if self.hostname == *conflicting {
+            let hostname = next_host_name(&self.hostname.to_string());
+            let old = mem::replace(&mut self.hostname, Name::from_str(hostname).unwrap());
+            self.notify_name_change(NameChange::Host { old });
+        }
+        self.hostname.clone()
+    }
+
+    /// Returns a stream of the new instance names of service `id`
+    pub fn subscribe_renames(&mut self, id: usize) -> mpsc::UnboundedReceiver<String> {
+        let (tx, rx) = mpsc::unbounded();
+        self.renames.insert(id, tx);
+        rx
+    }
+
+    /// Returns a stream of the names `rename` and `rename_host` give up
+    ///
+    /// Every FSM subscribes, so that a rename after a conflict seen by one
+    /// address family reaches the others too.
+    pub fn subscribe_name_changes(&mut self) -> mpsc::UnboundedReceiver<NameChange> {
+        let (tx, rx) = mpsc::unbounded();
+        self.name_changes.push(tx);
+        rx
+    }
+
+    fn notify_name_change(&mut self, change: NameChange) {
+        self.name_changes.retain(|tx| tx.unbounded_send(change.clone()).is_ok());
+    }
+
+    fn unused_name(&self, svc: &ServiceData) -> Name<'static> {
+        let mut instance = svc.instance();
+        loop {
+            instance = next_instance_name(&instance);
+            let name = Name::from_str(format!("{}.{}", instance, svc.typ))
+                .expect("instance name is too long");
+            if !self.by_name.contains_key(&name) {
+                return name;
+            }
+        }
+    }
+}
+
+/// Derives the next candidate host name after a conflict, e.g.
+/// "host.local" becomes "host-2.local" and "host-2.local" becomes
+/// "host-3.local"
+fn next_host_name(hostname: &str) -> String {
+    let (label, domain) = match hostname.find('.') {
+        Some(dot) => hostname.split_at(dot),
+        None => (hostname, ""),
+    };
+
+    let (base, n) = match label.rfind('-') {
+        Some(dash) => match label[dash + 1..].parse::<u32>() {
+            Ok(n) => (&label[..dash], n + 1),
+            Err(_) => (label, 2),
+        },
+        None => (label, 2),
+    };
+
+    let suffix = format!("-{}", n);
+    let mut base = base.to_owned();
+    while base.len() + suffix.len() > 63 {
+        base.pop();
+    }
+
+    base + &suffix + domain
+}
+
+/// Derives the next candidate instance name after a conflict,
+/// e.g. "Web Server" becomes "Web Server (2)" and "Web Server (2)"
+/// becomes "Web Server (3)" (RFC 6763 appendix D)
+fn next_instance_name(instance: &str) -> String {
+    let (base, n) = match instance.rfind(" (") {
+        Some(open) if instance.ends_with(')') => {
+            match instance[open + 2..instance.len() - 1].parse::<u32>() {
+                Ok(n) => (&instance[..open], n + 1),
+                Err(_) => (instance, 2),
+            }
+        }
+        _ => (instance, 2),
+    };
+
+    let suffix = format!(" ({})", n);
+    let mut base = base.to_owned();
+    // keep the whole label within the 63 byte limit
+    while base.len() + suffix.len() > 63 {
+        base.pop();
+    }
+
+    base + &suffix
 }
 
 /// Returned by [`ServicesInner.find_by_type`](struct.ServicesInner.html#method.find_by_type)
@@ -117,22 +278,129 @@ pub struct ServiceData {
     pub txt: Vec<u8>,
 }
 
-/// Packet building helpers for `fsm` to respond with `ServiceData`
 impl ServiceData {
-    pub fn add_ptr_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(&self.typ, QueryClass::IN, ttl, &RRData::PTR(self.name.clone()))
+    /// The instance part of the service name, e.g. "Web Server" for
+    /// "Web Server._http._tcp.local"
+    pub fn instance(&self) -> String {
+        let name = self.name.to_string();
+        let suffix = format!(".{}", self.typ);
+        if name.ends_with(&suffix) {
+            name[..name.len() - suffix.len()].to_owned()
+        } else {
+            name
+        }
+    }
+}
+
+/// Record building helpers for `fsm` to respond with `ServiceData`
+impl ServiceData {
+    pub fn ptr_record(&self, ttl: u32) -> Record {
+        Record::shared(&self.typ, ttl, &RRData::PTR(self.name.clone()))
+    }
+
+    pub fn srv_record(&self, hostname: &Name, ttl: u32) -> Record {
+        Record::new(&self.name, ttl, &self.srv_rdata(hostname))
+    }
+
+    pub fn txt_record(&self, ttl: u32) -> Record {
+        Record::new(&self.name, ttl, &self.txt_rdata())
+    }
+
+    /// Asserts that the instance name has no records besides SRV and TXT
+    pub fn nsec_record(&self, ttl: u32) -> Record {
+        let types = vec![Type::TXT, Type::SRV];
+        Record::new(&self.name, ttl, &RRData::NSEC { next: self.name.clone(), types })
     }
 
-    pub fn add_srv_rr(&self, hostname: &Name, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(&self.name, QueryClass::IN, ttl, &RRData::SRV {
+    pub fn srv_rdata<'a>(&self, hostname: &Name<'a>) -> RRData<'a> {
+        RRData::SRV {
             priority: 0,
             weight: 0,
             port: self.port,
             target: hostname.clone(),
-        })
+        }
+    }
+
+    pub fn txt_rdata(&self) -> RRData<'_> {
+        RRData::TXT(&self.txt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn service(instance: &str) -> ServiceData {
+        ServiceData {
+            name: Name::from_str(format!("{}._http._tcp.local", instance)).unwrap(),
+            typ: Name::from_str("_http._tcp.local").unwrap(),
+            port: 80,
+            txt: vec![0],
+        }
+    }
+
+    #[test]
+    fn next_instance_names() {
+        assert_eq!(next_instance_name("Web Server"), "Web Server (2)");
+        assert_eq!(next_instance_name("Web Server (2)"), "Web Server (3)");
+        assert_eq!(next_instance_name("Web Server (9)"), "Web Server (10)");
+        assert_eq!(next_instance_name("Web Server (x)"), "Web Server (x) (2)");
+        assert_eq!(next_instance_name(&"x".repeat(63)).len(), 63);
+    }
+
+    #[test]
+    fn next_host_names() {
+        assert_eq!(next_host_name("host.local"), "host-2.local");
+        assert_eq!(next_host_name("host-2.local"), "host-3.local");
+        assert_eq!(next_host_name("my-host.local"), "my-host-2.local");
+    }
+
+    #[test]
+    fn rename_service() {
+        let mut services = ServicesInner::new("host.local".to_owned());
+        let id = services.register(service("Web Server"));
+        let renames = services.subscribe_renames(id);
+        let conflicting = service("Web Server").name;
+
+        let name = services.rename(id, &conflicting).unwrap();
+        assert_eq!(name.to_string(), "Web Server (2)._http._tcp.local");
+        assert_eq!(services.find_id_by_name(&name), Some(id));
+        assert_eq!(services.find_id_by_name(&conflicting), None);
+
+        // the conflict was already resolved, so nothing changes
+        assert_eq!(services.rename(id, &conflicting), Some(name));
+
+        use futures::{Future, Stream};
+        let (first, _) = renames.into_future().wait().ok().unwrap();
+        assert_eq!(first, Some("Web Server (2)".to_owned()));
+    }
+
+    #[test]
+    fn name_changes() {
+        use futures::{Future, Stream};
+
+        let mut services = ServicesInner::new("host.local".to_owned());
+        let id = services.register(service("Web Server"));
+        let changes = services.subscribe_name_changes();
+        let host = services.get_hostname().clone();
+        let conflicting = service("Web Server").name;
+
+        services.rename(id, &conflicting);
+        services.rename(id, &conflicting);
+        services.rename_host(&host);
+        services.rename_host(&host);
+        drop(services);
+
+        let changes = changes.collect().wait().unwrap();
+        assert_eq!(changes, vec![NameChange::Service { id, old: conflicting },
+                                 NameChange::Host { old: host }]);
     }
 
-    pub fn add_txt_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(&self.name, QueryClass::IN, ttl, &RRData::TXT(&self.txt))
+    #[test]
+    fn register_duplicate_name() {
+        let mut services = ServicesInner::new("host.local".to_owned());
+        services.register(service("Web Server"));
+        let id = services.register(service("Web Server"));
+        assert_eq!(services.find_by_id(id).unwrap().instance(), "Web Server (2)");
     }
 }